@@ -15,7 +15,7 @@ pub struct Args {
     pub reset_config: bool,
 }
 
-fn get_clap_styles() -> clap::builder::Styles {
+pub fn get_clap_styles() -> clap::builder::Styles {
     clap::builder::Styles::styled()
         .usage(
             anstyle::Style::new()
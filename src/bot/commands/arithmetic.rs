@@ -0,0 +1,516 @@
+//! `$(( ... ))` expressions, C-like precedence over `i128`
+
+use super::{
+    diagnostic::Diagnostic,
+    parser::{Environ, EnvironValue, ParseError},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Expr {
+    Literal(i128),
+    /// Both `NAME` and `$NAME` reference a variable, missing ones are `0`
+    Var(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UnaryOp {
+    /// `-`
+    Neg,
+    /// `+`
+    Plus,
+    /// `!`
+    Not,
+    /// `~`
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BinaryOp {
+    Pow,
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    BitAnd,
+    BitXor,
+    BitOr,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArithmeticError {
+    Overflow,
+    DivisionByZero,
+    NegativeExponent,
+    /// Variable that can't be read as an integer
+    NotANumber(String),
+}
+
+impl std::fmt::Display for ArithmeticError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Overflow => write!(f, "arithmetic overflow"),
+            Self::DivisionByZero => write!(f, "division by zero"),
+            Self::NegativeExponent => write!(f, "negative exponent"),
+            Self::NotANumber(name) => write!(f, "`{name}` is not a number"),
+        }
+    }
+}
+
+impl Diagnostic for ArithmeticError {
+    fn code(&self) -> Option<&'static str> {
+        Some(match self {
+            Self::Overflow => "E411",
+            Self::DivisionByZero => "E412",
+            Self::NegativeExponent => "E413",
+            Self::NotANumber(_) => "E414",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i128),
+    Ident(String),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+impl BinaryOp {
+    /// Sorted so longer operators match first
+    const TOKENS: &'static [(&'static str, BinaryOp)] = &[
+        ("**", Self::Pow),
+        ("<<", Self::Shl),
+        (">>", Self::Shr),
+        ("<=", Self::Le),
+        (">=", Self::Ge),
+        ("==", Self::Eq),
+        ("!=", Self::Ne),
+        ("&&", Self::And),
+        ("||", Self::Or),
+        ("*", Self::Mul),
+        ("/", Self::Div),
+        ("%", Self::Rem),
+        ("+", Self::Add),
+        ("-", Self::Sub),
+        ("<", Self::Lt),
+        (">", Self::Gt),
+        ("&", Self::BitAnd),
+        ("^", Self::BitXor),
+        ("|", Self::BitOr),
+    ];
+
+    fn from_token(token: &str) -> Option<Self> {
+        Self::TOKENS
+            .iter()
+            .find(|(op, _)| *op == token)
+            .map(|(_, op)| *op)
+    }
+
+    /// Higher binds tighter
+    fn precedence(self) -> u8 {
+        match self {
+            Self::Pow => 10,
+            Self::Mul | Self::Div | Self::Rem => 9,
+            Self::Add | Self::Sub => 8,
+            Self::Shl | Self::Shr => 7,
+            Self::Lt | Self::Le | Self::Gt | Self::Ge => 6,
+            Self::Eq | Self::Ne => 5,
+            Self::BitAnd => 4,
+            Self::BitXor => 3,
+            Self::BitOr => 2,
+            Self::And => 1,
+            Self::Or => 0,
+        }
+    }
+
+    fn right_assoc(self) -> bool {
+        self == Self::Pow
+    }
+
+    fn apply(self, lhs: i128, rhs: i128) -> Result<i128, ArithmeticError> {
+        let shift = || u32::try_from(rhs).map_err(|_| ArithmeticError::Overflow);
+
+        match self {
+            Self::Pow => {
+                let exp = u32::try_from(rhs).map_err(|_| match rhs < 0 {
+                    true => ArithmeticError::NegativeExponent,
+                    false => ArithmeticError::Overflow,
+                })?;
+                lhs.checked_pow(exp).ok_or(ArithmeticError::Overflow)
+            }
+            Self::Mul => lhs.checked_mul(rhs).ok_or(ArithmeticError::Overflow),
+            Self::Div | Self::Rem if rhs == 0 => Err(ArithmeticError::DivisionByZero),
+            Self::Div => lhs.checked_div(rhs).ok_or(ArithmeticError::Overflow),
+            Self::Rem => lhs.checked_rem(rhs).ok_or(ArithmeticError::Overflow),
+            Self::Add => lhs.checked_add(rhs).ok_or(ArithmeticError::Overflow),
+            Self::Sub => lhs.checked_sub(rhs).ok_or(ArithmeticError::Overflow),
+            Self::Shl => {
+                let shift = shift()?;
+                // bits shifted out are an overflow too
+                lhs.checked_shl(shift)
+                    .filter(|res| res >> shift == lhs)
+                    .ok_or(ArithmeticError::Overflow)
+            }
+            Self::Shr => lhs.checked_shr(shift()?).ok_or(ArithmeticError::Overflow),
+            Self::Lt => Ok((lhs < rhs).into()),
+            Self::Le => Ok((lhs <= rhs).into()),
+            Self::Gt => Ok((lhs > rhs).into()),
+            Self::Ge => Ok((lhs >= rhs).into()),
+            Self::Eq => Ok((lhs == rhs).into()),
+            Self::Ne => Ok((lhs != rhs).into()),
+            Self::BitAnd => Ok(lhs & rhs),
+            Self::BitXor => Ok(lhs ^ rhs),
+            Self::BitOr => Ok(lhs | rhs),
+            Self::And => Ok((lhs != 0 && rhs != 0).into()),
+            Self::Or => Ok((lhs != 0 || rhs != 0).into()),
+        }
+    }
+}
+
+/// Only adds the parentheses precedence needs, so it parses back to the
+/// same tree
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Literal(n) => write!(f, "{n}"),
+            Self::Var(name) => write!(f, "{name}"),
+            Self::Unary(op, expr) => {
+                let op = match op {
+                    UnaryOp::Neg => "-",
+                    UnaryOp::Plus => "+",
+                    UnaryOp::Not => "!",
+                    UnaryOp::BitNot => "~",
+                };
+                match **expr {
+                    Self::Binary(..) => write!(f, "{op}({expr})"),
+                    _ => write!(f, "{op}{expr}"),
+                }
+            }
+            Self::Binary(op, lhs, rhs) => {
+                let binds_looser = |expr: &Self, right: bool| match expr {
+                    Self::Binary(inner, ..) => {
+                        inner.precedence() < op.precedence()
+                            || (inner.precedence() == op.precedence() && right != op.right_assoc())
+                    }
+                    _ => false,
+                };
+                let token = Self::token(*op);
+
+                match binds_looser(lhs, false) {
+                    true => write!(f, "({lhs}) {token} ")?,
+                    false => write!(f, "{lhs} {token} ")?,
+                }
+                match binds_looser(rhs, true) {
+                    true => write!(f, "({rhs})"),
+                    false => write!(f, "{rhs}"),
+                }
+            }
+        }
+    }
+}
+
+/// How deep the parser is, and how deep it can go
+#[derive(Debug, Clone, Copy)]
+struct Depth(usize, Option<usize>);
+
+impl Depth {
+    fn nest(self) -> Result<Self, ParseError> {
+        let Self(depth, max_depth) = self;
+        match max_depth {
+            Some(max_depth) if depth >= max_depth => Err(ParseError::NestingTooDeep(max_depth)),
+            _ => Ok(Self(depth + 1, max_depth)),
+        }
+    }
+}
+
+impl Expr {
+    fn token(op: BinaryOp) -> &'static str {
+        BinaryOp::TOKENS
+            .iter()
+            .find(|(_, token_op)| *token_op == op)
+            .map_or("", |(token, _)| token)
+    }
+
+    /// Parens, unary ops and chains of `**` or `=` can nest `max_depth`
+    /// levels deep, see [`super::parser::MsgParser::max_depth`]
+    pub fn parse(expr: &str, max_depth: Option<usize>) -> Result<Self, ParseError> {
+        let mut tokens = Self::tokenize(expr)?.into_iter().peekable();
+        let expr = Self::parse_binary(&mut tokens, 0, Depth(0, max_depth))?;
+
+        if tokens.next().is_some() {
+            do yeet ParseError::InvalidArithmetic;
+        }
+        Ok(expr)
+    }
+
+    fn tokenize(expr: &str) -> Result<Vec<Token>, ParseError> {
+        let mut tokens = vec![];
+        let mut rest = expr.trim_start();
+
+        while let Some(ch) = rest.chars().next() {
+            let is_name = |c: char| c.is_alphanumeric() || c == '_';
+
+            let len = if ch.is_ascii_digit() {
+                let len = rest
+                    .find(|c: char| !c.is_alphanumeric())
+                    .unwrap_or(rest.len());
+                tokens.push(Token::Number(Self::parse_number(&rest[..len])?));
+                len
+            } else if ch == '$' || is_name(ch) {
+                let (name, len) = match rest.strip_prefix("${") {
+                    Some(name) => {
+                        let end = name.find('}').ok_or(ParseError::InvalidArithmetic)?;
+                        (&name[..end], end + 3)
+                    }
+                    None => {
+                        let name = rest.strip_prefix('$').unwrap_or(rest);
+                        let end = name.find(|c| !is_name(c)).unwrap_or(name.len());
+                        (&name[..end], end + rest.len() - name.len())
+                    }
+                };
+
+                if name.is_empty() || !name.chars().all(is_name) {
+                    do yeet ParseError::InvalidArithmetic;
+                }
+                tokens.push(Token::Ident(name.to_owned()));
+                len
+            } else if ch == '(' {
+                tokens.push(Token::Open);
+                1
+            } else if ch == ')' {
+                tokens.push(Token::Close);
+                1
+            } else {
+                let op = BinaryOp::TOKENS
+                    .iter()
+                    .map(|(op, _)| *op)
+                    .chain(["!", "~"])
+                    .find(|op| rest.starts_with(op))
+                    .ok_or(ParseError::InvalidArithmetic)?;
+                tokens.push(Token::Op(op));
+                op.len()
+            };
+
+            rest = rest[len..].trim_start();
+        }
+
+        Ok(tokens)
+    }
+
+    /// Decimal, or `0x`, `0o` and `0b` prefixed
+    fn parse_number(number: &str) -> Result<i128, ParseError> {
+        let (digits, radix) = match number.get(..2) {
+            Some("0x") => (&number[2..], 16),
+            Some("0o") => (&number[2..], 8),
+            Some("0b") => (&number[2..], 2),
+            _ => (number, 10),
+        };
+
+        i128::from_str_radix(digits, radix).map_err(|_| ParseError::InvalidArithmetic)
+    }
+
+    fn parse_binary(
+        tokens: &mut std::iter::Peekable<impl Iterator<Item = Token>>,
+        min_precedence: u8,
+        depth: Depth,
+    ) -> Result<Self, ParseError> {
+        let mut lhs = Self::parse_unary(tokens, depth)?;
+
+        while let Some(Token::Op(op)) = tokens.peek() {
+            let Some(op) = BinaryOp::from_token(op) else {
+                do yeet ParseError::InvalidArithmetic;
+            };
+            if op.precedence() < min_precedence {
+                break;
+            }
+            tokens.next();
+
+            // left associative ones can only recurse once per precedence
+            let rhs = match op.right_assoc() {
+                true => Self::parse_binary(tokens, op.precedence(), depth.nest()?)?,
+                false => Self::parse_binary(tokens, op.precedence() + 1, depth)?,
+            };
+            lhs = Self::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn parse_unary(
+        tokens: &mut std::iter::Peekable<impl Iterator<Item = Token>>,
+        depth: Depth,
+    ) -> Result<Self, ParseError> {
+        match tokens.next().ok_or(ParseError::InvalidArithmetic)? {
+            Token::Number(n) => Ok(Self::Literal(n)),
+            Token::Ident(name) => Ok(Self::Var(name)),
+            Token::Open => {
+                let expr = Self::parse_binary(tokens, 0, depth.nest()?)?;
+                match tokens.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err(ParseError::InvalidArithmetic),
+                }
+            }
+            Token::Op(op) => {
+                let op = match op {
+                    "-" => UnaryOp::Neg,
+                    "+" => UnaryOp::Plus,
+                    "!" => UnaryOp::Not,
+                    "~" => UnaryOp::BitNot,
+                    _ => do yeet ParseError::InvalidArithmetic,
+                };
+                // unary ops bind tighter than `**`, so `-2**2` is 4 like bash
+                Ok(Self::Unary(
+                    op,
+                    Box::new(Self::parse_unary(tokens, depth.nest()?)?),
+                ))
+            }
+            Token::Close => Err(ParseError::InvalidArithmetic),
+        }
+    }
+
+    pub fn eval<'a>(&self, env: &impl Environ<'a>) -> Result<i128, ArithmeticError> {
+        match self {
+            Self::Literal(n) => Ok(*n),
+            Self::Var(name) => match env.get(name).as_deref() {
+                None | Some(EnvironValue::None) => Ok(0),
+                Some(EnvironValue::Number(n)) => Ok(*n),
+                Some(EnvironValue::UNumber(n)) => {
+                    i128::try_from(*n).map_err(|_| ArithmeticError::Overflow)
+                }
+                Some(EnvironValue::String(value)) => value
+                    .to_str()
+                    .and_then(|value| value.trim().parse().ok())
+                    .ok_or_else(|| ArithmeticError::NotANumber(name.clone())),
+                Some(_) => Err(ArithmeticError::NotANumber(name.clone())),
+            },
+            Self::Unary(op, expr) => {
+                let value = expr.eval(env)?;
+                match op {
+                    UnaryOp::Neg => value.checked_neg().ok_or(ArithmeticError::Overflow),
+                    UnaryOp::Plus => Ok(value),
+                    UnaryOp::Not => Ok((value == 0).into()),
+                    UnaryOp::BitNot => Ok(!value),
+                }
+            }
+            Self::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(env)?;
+                // short-circuit like C
+                match (op, lhs) {
+                    (BinaryOp::And, 0) => Ok(0),
+                    (BinaryOp::Or, lhs) if lhs != 0 => Ok(1),
+                    _ => op.apply(lhs, rhs.eval(env)?),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::DefaultEnviron, *};
+
+    fn eval(expr: &str) -> Result<i128, ArithmeticError> {
+        eval_in(expr, &DefaultEnviron::default())
+    }
+
+    fn eval_in<'a>(expr: &str, env: &impl Environ<'a>) -> Result<i128, ArithmeticError> {
+        Expr::parse(expr, None).unwrap().eval(env)
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("10 - 4 - 3"), Ok(3));
+        assert_eq!(eval("1 << 2 + 1"), Ok(8));
+        assert_eq!(eval("1 | 2 ^ 3 & 7"), Ok(1));
+        assert_eq!(eval("1 < 2 == 1 && 0 || 3"), Ok(1));
+        assert_eq!(eval("-2 ** 2"), Ok(4));
+        assert_eq!(eval("0x10 + 0o10 + 0b10"), Ok(26));
+    }
+
+    #[test]
+    fn pow_is_right_associative() {
+        assert_eq!(eval("2 ** 3 ** 2"), Ok(512));
+        assert_eq!(eval("(2 ** 3) ** 2"), Ok(64));
+    }
+
+    #[test]
+    fn variables() {
+        let mut env = DefaultEnviron::default();
+        env.insert(
+            String::from("n"),
+            EnvironValue::String(" 6 ".into()),
+            Default::default(),
+        );
+        env.insert(
+            String::from("list"),
+            EnvironValue::Array(vec![]),
+            Default::default(),
+        );
+        assert_eq!(eval_in("n * $n + ${n}", &env), Ok(42));
+        assert_eq!(eval_in("missing + 1", &env), Ok(1));
+        assert_eq!(
+            eval_in("list", &env),
+            Err(ArithmeticError::NotANumber(String::from("list")))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            eval("170141183460469231731687303715884105727 + 1"),
+            Err(ArithmeticError::Overflow)
+        );
+        assert_eq!(eval("2 ** 127"), Err(ArithmeticError::Overflow));
+        assert_eq!(eval("1 << 127 << 1"), Err(ArithmeticError::Overflow));
+        assert_eq!(
+            eval("-(-170141183460469231731687303715884105727 - 1)"),
+            Err(ArithmeticError::Overflow)
+        );
+        assert_eq!(eval("1 / 0"), Err(ArithmeticError::DivisionByZero));
+        assert_eq!(eval("1 % (2 - 2)"), Err(ArithmeticError::DivisionByZero));
+        assert_eq!(eval("2 ** -1"), Err(ArithmeticError::NegativeExponent));
+    }
+
+    #[test]
+    fn short_circuits() {
+        assert_eq!(eval("0 && 1 / 0"), Ok(0));
+        assert_eq!(eval("1 || 1 / 0"), Ok(1));
+    }
+
+    #[test]
+    fn nesting_is_bounded() {
+        assert!(Expr::parse("((((1))))", Some(4)).is_ok());
+        assert!(matches!(
+            Expr::parse("(((((1)))))", Some(4)),
+            Err(ParseError::NestingTooDeep(4))
+        ));
+        assert!(matches!(
+            Expr::parse("- - - - - 1", Some(4)),
+            Err(ParseError::NestingTooDeep(4))
+        ));
+        assert!(matches!(
+            Expr::parse("2 ** 2 ** 2 ** 2 ** 2 ** 2", Some(4)),
+            Err(ParseError::NestingTooDeep(4))
+        ));
+        // left associative chains don't nest
+        assert!(Expr::parse(&["1"; 100].join(" + "), Some(4)).is_ok());
+    }
+}
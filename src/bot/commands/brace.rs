@@ -0,0 +1,258 @@
+//! Bash-like brace expansion, `pre{a,b}post` and `{1..10..2}` ranges
+
+use super::parser::{ParseError, ShellArg};
+
+/// Words a single word can expand to
+pub const MAX_EXPANSION: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Arg(ShellArg),
+    Open,
+    Comma,
+    Close,
+}
+
+/// Index of the closing `}` and the alternatives inside
+type Group = (usize, Vec<Vec<Piece>>);
+
+impl Piece {
+    fn literal(pieces: &[Self]) -> Vec<ShellArg> {
+        pieces
+            .iter()
+            .map(|piece| match piece {
+                Self::Arg(arg) => arg.clone(),
+                Self::Open => ShellArg::Char('{'),
+                Self::Comma => ShellArg::Char(','),
+                Self::Close => ShellArg::Char('}'),
+            })
+            .collect()
+    }
+}
+
+/// `marks` are the indexes of unquoted, unescaped `{`, `,` and `}` chars in
+/// `word`, only those can form a brace expression
+pub fn expand(word: Vec<ShellArg>, marks: &[usize]) -> Result<Vec<Vec<ShellArg>>, ParseError> {
+    let pieces: Vec<_> = word
+        .into_iter()
+        .enumerate()
+        .map(|(i, arg)| match arg {
+            ShellArg::Char('{') if marks.contains(&i) => Piece::Open,
+            ShellArg::Char(',') if marks.contains(&i) => Piece::Comma,
+            ShellArg::Char('}') if marks.contains(&i) => Piece::Close,
+            arg => Piece::Arg(arg),
+        })
+        .collect();
+
+    expand_pieces(&pieces)
+}
+
+fn expand_pieces(pieces: &[Piece]) -> Result<Vec<Vec<ShellArg>>, ParseError> {
+    // the first brace that forms a valid expression, invalid ones are literal
+    let mut found = None;
+    for (open, piece) in pieces.iter().enumerate() {
+        if *piece == Piece::Open {
+            if let Some(group) = group(pieces, open)? {
+                found = Some((open, group));
+                break;
+            }
+        }
+    }
+
+    let Some((open, (close, alternatives))) = found else {
+        return Ok(vec![Piece::literal(pieces)]);
+    };
+
+    let prefix = Piece::literal(&pieces[..open]);
+    let suffixes = expand_pieces(&pieces[close + 1..])?;
+
+    let mut words = vec![];
+    for alternative in alternatives {
+        let alternatives = expand_pieces(&alternative)?;
+        if words.len() + alternatives.len() * suffixes.len() > MAX_EXPANSION {
+            do yeet ParseError::BraceExpansionTooLarge;
+        }
+
+        for alternative in alternatives {
+            for suffix in &suffixes {
+                words.push([&prefix[..], &alternative, suffix].concat());
+            }
+        }
+    }
+
+    Ok(words)
+}
+
+/// Matching `}` of the `{` at `open` and its alternatives, if there's a
+/// comma or a range between them
+fn group(pieces: &[Piece], open: usize) -> Result<Option<Group>, ParseError> {
+    let mut depth = 0;
+    let mut commas = vec![];
+
+    for (i, piece) in pieces.iter().enumerate().skip(open + 1) {
+        match piece {
+            Piece::Open => depth += 1,
+            Piece::Close if depth > 0 => depth -= 1,
+            Piece::Close => {
+                if commas.is_empty() {
+                    return Ok(range(&pieces[open + 1..i])?.map(|range| (i, range)));
+                }
+
+                let mut alternatives = vec![];
+                let mut start = open + 1;
+                for comma in commas.into_iter().chain([i]) {
+                    alternatives.push(pieces[start..comma].to_vec());
+                    start = comma + 1;
+                }
+                return Ok(Some((i, alternatives)));
+            }
+            Piece::Comma if depth == 0 => commas.push(i),
+            _ => {}
+        }
+    }
+
+    Ok(None)
+}
+
+/// `x..y` or `x..y..step`, of either integers or single ASCII letters
+fn range(inner: &[Piece]) -> Result<Option<Vec<Vec<Piece>>>, ParseError> {
+    let Some(inner) = inner
+        .iter()
+        .map(|piece| match piece {
+            Piece::Arg(ShellArg::Char(ch)) => Some(*ch),
+            _ => None,
+        })
+        .collect::<Option<String>>()
+    else {
+        return Ok(None);
+    };
+
+    let parts: Vec<_> = inner.split("..").collect();
+    let (start, end, step) = match parts[..] {
+        [start, end] => (start, end, 1),
+        [start, end, step] => match step.parse::<i64>() {
+            Ok(step) => (start, end, step.unsigned_abs().max(1)),
+            Err(_) => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+
+    let word = |value: String| {
+        value
+            .chars()
+            .map(|c| Piece::Arg(ShellArg::Char(c)))
+            .collect()
+    };
+
+    if let (Ok(first), Ok(last)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        // `{01..10}` pads every number to the widest end
+        let padded = [start, end].iter().any(|n| {
+            let digits = n.trim_start_matches('-');
+            digits.len() > 1 && digits.starts_with('0')
+        });
+        let width = if padded {
+            start.len().max(end.len())
+        } else {
+            0
+        };
+
+        return Ok(Some(
+            steps(first, last, step)?
+                .map(|n| word(format!("{n:0width$}")))
+                .collect(),
+        ));
+    }
+
+    let letter = |value: &str| {
+        let mut chars = value.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_alphabetic() => Some(c),
+            _ => None,
+        }
+    };
+    let (Some(first), Some(last)) = (letter(start), letter(end)) else {
+        return Ok(None);
+    };
+
+    Ok(Some(
+        steps(first as i64, last as i64, step)?
+            .map(|c| word(char::from(c as u8).to_string()))
+            .collect(),
+    ))
+}
+
+/// From `start` to `end` included, going down if `end` is smaller. Offsets
+/// are unsigned, so neither the full `i64` range nor a `2^63` step overflow.
+fn steps(start: i64, end: i64, step: u64) -> Result<impl Iterator<Item = i64>, ParseError> {
+    let count = (start.abs_diff(end) / step)
+        .checked_add(1)
+        .filter(|count| *count <= MAX_EXPANSION as u64)
+        .ok_or(ParseError::BraceExpansionTooLarge)?;
+
+    Ok((0..count).map(move |i| {
+        // at most `start.abs_diff(end)`, so the result is always in range
+        let offset = i * step;
+        match end < start {
+            true => start.wrapping_sub_unsigned(offset),
+            false => start.wrapping_add_unsigned(offset),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every `{`, `,` and `}` is unquoted
+    fn expand_str(word: &str) -> Result<Vec<String>, ParseError> {
+        let word: Vec<_> = word.chars().map(ShellArg::Char).collect();
+        let marks: Vec<_> = word
+            .iter()
+            .enumerate()
+            .filter(|(_, arg)| matches!(arg, ShellArg::Char('{' | ',' | '}')))
+            .map(|(i, _)| i)
+            .collect();
+
+        Ok(expand(word, &marks)?
+            .into_iter()
+            .map(|word| {
+                word.into_iter()
+                    .map(|arg| match arg {
+                        ShellArg::Char(c) => c,
+                        arg => panic!("unexpected {arg:?}"),
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(expand_str("{1..3}").unwrap(), ["1", "2", "3"]);
+        assert_eq!(expand_str("{3..1}").unwrap(), ["3", "2", "1"]);
+        assert_eq!(expand_str("{0..10..-5}").unwrap(), ["0", "5", "10"]);
+        assert_eq!(expand_str("{a..e..2}").unwrap(), ["a", "c", "e"]);
+    }
+
+    #[test]
+    fn full_range_is_too_large() {
+        assert!(matches!(
+            expand_str("{-9223372036854775808..9223372036854775807}"),
+            Err(ParseError::BraceExpansionTooLarge)
+        ));
+    }
+
+    #[test]
+    fn extreme_steps() {
+        assert_eq!(expand_str("{1..0..-9223372036854775808}").unwrap(), ["1"]);
+        assert_eq!(
+            expand_str("{9223372036854775807..-9223372036854775808..9223372036854775807}").unwrap(),
+            ["9223372036854775807", "0", "-9223372036854775807"]
+        );
+        assert_eq!(
+            expand_str("{-9223372036854775808..9223372036854775807..-9223372036854775808}")
+                .unwrap(),
+            ["-9223372036854775808", "0"]
+        );
+    }
+}
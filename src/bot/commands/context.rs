@@ -0,0 +1,91 @@
+//! Variables about the message being run, where and when it was sent
+
+use super::{
+    parser::{Attributes, EnvironValue},
+    LazyEnviron,
+};
+
+use std::{
+    ffi::OsString,
+    hash::{BuildHasher, RandomState},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serenity::{model::channel::Message, prelude::*};
+
+/// They describe the message, so they can't be spoofed by setting them
+const CONTEXT: Attributes = Attributes {
+    readonly: true,
+    exported: true,
+    secret: false,
+    kind: None,
+};
+
+fn string(value: impl Into<OsString>) -> EnvironValue {
+    EnvironValue::String(value.into())
+}
+
+fn number(value: u64) -> EnvironValue {
+    EnvironValue::UNumber(value.into())
+}
+
+/// The guild ones and `CHANNEL` are unset in DMs, names and counts come from
+/// the cache, filled with the `GUILDS` intent, and are only looked up when
+/// used
+pub fn environ(ctx: &Context, msg: &Message, prefix: char) -> LazyEnviron<'static> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+
+    let mut environ = LazyEnviron::default();
+    let mut seed = |key: &str, value| {
+        environ.insert(key.to_owned(), value, CONTEXT);
+    };
+    seed("USER", string(&msg.author.name));
+    seed("USERID", number(msg.author.id.get()));
+    seed("CHANNELID", number(msg.channel_id.get()));
+    seed("MSGID", number(msg.id.get()));
+    seed("NOW", number(now.as_secs()));
+    seed("PREFIX", string(prefix.to_string()));
+    seed("HOSTNAME", string(hostname.trim_end()));
+    seed("PID", number(std::process::id().into()));
+    if let Some(replied) = &msg.referenced_message {
+        seed("REPLYTO", string(&replied.content));
+    }
+    // like bash, 0 to 32767 and different every time it's read. Each
+    // `RandomState` has new keys.
+    environ.volatile(String::from("RANDOM"), CONTEXT, || {
+        number(RandomState::new().hash_one(SystemTime::now()) % 32768)
+    });
+
+    if let Some(guild_id) = msg.guild_id {
+        environ.insert(String::from("GUILDID"), number(guild_id.get()), CONTEXT);
+
+        let (cache, channel_id) = (ctx.cache.clone(), msg.channel_id);
+        environ.lazy(String::from("CHANNEL"), CONTEXT, move || {
+            cache
+                .guild(guild_id)
+                .and_then(|guild| Some(string(&guild.channels.get(&channel_id)?.name)))
+                .unwrap_or_default()
+        });
+
+        let cache = ctx.cache.clone();
+        environ.lazy(String::from("GUILD"), CONTEXT, move || {
+            cache
+                .guild(guild_id)
+                .map(|guild| string(&guild.name))
+                .unwrap_or_default()
+        });
+
+        let cache = ctx.cache.clone();
+        environ.lazy(String::from("MEMBERS"), CONTEXT, move || {
+            cache
+                .guild(guild_id)
+                .map(|guild| number(guild.member_count))
+                .unwrap_or_default()
+        });
+    }
+
+    environ
+}
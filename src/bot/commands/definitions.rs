@@ -0,0 +1,384 @@
+//! User-defined functions and aliases, stored in SurrealDB per user and per
+//! guild, one record per definition
+
+use super::{
+    help,
+    list::COMMAND_MAP,
+    parser::{is_command_name, CommandList, EnvironValue, Output, ParseCtx},
+    permissions::Requirement,
+};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use serenity::model::{
+    id::{GuildId, UserId},
+    Permissions,
+};
+
+const USER_TABLE: &str = "user_definitions";
+const GUILD_TABLE: &str = "guild_definitions";
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Scope {
+    pub functions: HashMap<String, CommandList>,
+    pub aliases: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum Owner {
+    User,
+    Guild,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum Kind {
+    Function,
+    Alias,
+}
+
+/// A stored definition, functions are kept as their source so changes to
+/// the AST don't break them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    /// The user's or the guild's id
+    owner: u64,
+    kind: Kind,
+    name: String,
+    source: String,
+}
+
+/// Definitions visible to a message, the user's ones shadow the guild's
+#[derive(Debug, Default, Clone)]
+pub struct Definitions {
+    pub user: Scope,
+    /// `None` outside of guilds
+    pub guild: Option<Scope>,
+    /// Where the scopes get stored back, `None` if they weren't loaded
+    user_id: Option<UserId>,
+    guild_id: Option<GuildId>,
+    /// Only these get written back, so messages running at the same time
+    /// don't undo each other's definitions
+    changed: HashSet<(Owner, Kind, String)>,
+}
+
+impl Definitions {
+    /// Builtins handled by [`Definitions::builtin`]
+    pub const BUILTINS: &'static [&'static str] =
+        &["alias", "unalias", "unfunction", "share", "unshare"];
+
+    /// What its builtins need, the guild's definitions are everyone's
+    pub fn requirement(builtin: &str) -> Option<Requirement> {
+        match builtin {
+            "share" | "unshare" => Some(Requirement::permissions(Permissions::MANAGE_GUILD)),
+            builtin if Self::BUILTINS.contains(&builtin) => Some(Requirement::default()),
+            _ => None,
+        }
+    }
+
+    pub async fn load(
+        db: &surrealdb::Surreal<impl surrealdb::Connection>,
+        user_id: UserId,
+        guild_id: Option<GuildId>,
+    ) -> surrealdb::Result<Self> {
+        let user = load_scope(db, USER_TABLE, user_id.get()).await?;
+        let guild = match guild_id {
+            Some(guild_id) => Some(load_scope(db, GUILD_TABLE, guild_id.get()).await?),
+            None => None,
+        };
+
+        Ok(Self {
+            user,
+            guild,
+            user_id: Some(user_id),
+            guild_id,
+            ..Default::default()
+        })
+    }
+
+    /// Only writes the definitions that changed, removed ones get deleted.
+    /// Nothing is written if they weren't loaded.
+    pub async fn store(
+        &self,
+        db: &surrealdb::Surreal<impl surrealdb::Connection>,
+    ) -> surrealdb::Result<()> {
+        for (owner, kind, name) in &self.changed {
+            let (table, owner_id, scope) = match owner {
+                Owner::User => (USER_TABLE, self.user_id.map(UserId::get), Some(&self.user)),
+                Owner::Guild => (
+                    GUILD_TABLE,
+                    self.guild_id.map(GuildId::get),
+                    self.guild.as_ref(),
+                ),
+            };
+            let (Some(owner_id), Some(scope)) = (owner_id, scope) else {
+                continue;
+            };
+
+            let source = match kind {
+                Kind::Function => scope.functions.get(name).map(ToString::to_string),
+                Kind::Alias => scope.aliases.get(name).cloned(),
+            };
+            let id = record_id(owner_id, *kind, name);
+            match source {
+                Some(source) => {
+                    let record = Record {
+                        owner: owner_id,
+                        kind: *kind,
+                        name: name.clone(),
+                        source,
+                    };
+                    let _: Option<Record> = db.update((table, id)).content(record).await?;
+                }
+                None => {
+                    let _: Option<Record> = db.delete((table, id)).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn change(&mut self, owner: Owner, kind: Kind, name: &str) {
+        self.changed.insert((owner, kind, name.to_owned()));
+    }
+
+    pub fn function(&self, name: &str) -> Option<&CommandList> {
+        self.user
+            .functions
+            .get(name)
+            .or_else(|| self.guild.as_ref()?.functions.get(name))
+    }
+
+    pub fn alias(&self, name: &str) -> Option<&str> {
+        self.user
+            .aliases
+            .get(name)
+            .or_else(|| self.guild.as_ref()?.aliases.get(name))
+            .map(String::as_str)
+    }
+
+    pub fn define_function(&mut self, name: String, body: CommandList) {
+        self.change(Owner::User, Kind::Function, &name);
+        self.user.functions.insert(name, body);
+    }
+
+    /// Definition management builtins, `None` if `args` isn't one of
+    /// [`Definitions::BUILTINS`]
+    pub fn builtin(&mut self, args: &[EnvironValue]) -> Option<Output> {
+        let help = help::builtin_help(args);
+        let args: Option<Vec<String>> = args
+            .iter()
+            .map(|arg| arg.clone().as_string()?.into_string().ok())
+            .collect();
+        let args = args?;
+        let (cmd, args) = args.split_first()?;
+
+        if !Self::BUILTINS.contains(&cmd.as_str()) {
+            return None;
+        }
+        if help.is_some() {
+            return help;
+        }
+
+        Some(match (cmd.as_str(), args) {
+            ("alias", []) => self.list_aliases(),
+            ("alias", args) => {
+                let mut printed = vec![];
+                for arg in args {
+                    let Some((name, value)) = arg.split_once('=') else {
+                        let Some(value) = self.alias(arg) else {
+                            return Some(Output::failure(1, format!("alias: {arg}: not found\n")));
+                        };
+                        printed.push(format!("alias {arg}={}", quote(value)));
+                        continue;
+                    };
+
+                    if !is_command_name(name) {
+                        return Some(Output::failure(1, format!("alias: {name}: invalid name\n")));
+                    }
+                    if ParseCtx::from_chars(&mut value.chars()).is_err() {
+                        return Some(Output::failure(
+                            1,
+                            format!("alias: {name}: doesn't parse\n"),
+                        ));
+                    }
+
+                    self.user.aliases.insert(name.to_owned(), value.to_owned());
+                    self.change(Owner::User, Kind::Alias, name);
+                }
+                lines(printed)
+            }
+            ("unalias", [name]) => match self.user.aliases.remove(name) {
+                Some(_) => {
+                    self.change(Owner::User, Kind::Alias, name);
+                    Output::default()
+                }
+                None => Output::failure(1, format!("unalias: {name}: not found\n")),
+            },
+            ("unfunction", [name]) => match self.user.functions.remove(name) {
+                Some(_) => {
+                    self.change(Owner::User, Kind::Function, name);
+                    Output::default()
+                }
+                None => Output::failure(1, format!("unfunction: {name}: not found\n")),
+            },
+            ("share", [name]) => {
+                let Some(guild) = &mut self.guild else {
+                    return Some(Output::failure(1, "share: not in a guild\n"));
+                };
+                // they'd run instead of the command for everyone in the guild
+                if COMMAND_MAP.contains_key(name.as_str())
+                    || help::BUILTINS.iter().any(|cmd| cmd.get_name() == name)
+                {
+                    return Some(Output::failure(
+                        1,
+                        format!("share: {name}: shadows a command\n"),
+                    ));
+                }
+
+                let function = self.user.functions.get(name);
+                let alias = self.user.aliases.get(name);
+                if function.is_none() && alias.is_none() {
+                    return Some(Output::failure(1, format!("share: {name}: not found\n")));
+                }
+
+                if let Some(function) = function {
+                    guild.functions.insert(name.clone(), function.clone());
+                    self.changed
+                        .insert((Owner::Guild, Kind::Function, name.clone()));
+                }
+                if let Some(alias) = alias {
+                    guild.aliases.insert(name.clone(), alias.clone());
+                    self.changed
+                        .insert((Owner::Guild, Kind::Alias, name.clone()));
+                }
+                Output::default()
+            }
+            ("unshare", [name]) => {
+                let Some(guild) = &mut self.guild else {
+                    return Some(Output::failure(1, "unshare: not in a guild\n"));
+                };
+
+                let function = guild.functions.remove(name);
+                let alias = guild.aliases.remove(name);
+                if function.is_none() && alias.is_none() {
+                    return Some(Output::failure(1, format!("unshare: {name}: not found\n")));
+                }
+                if function.is_some() {
+                    self.change(Owner::Guild, Kind::Function, name);
+                }
+                if alias.is_some() {
+                    self.change(Owner::Guild, Kind::Alias, name);
+                }
+                Output::default()
+            }
+            (cmd, _) => Output::failure(1, format!("{cmd}: invalid arg count\n")),
+        })
+    }
+
+    /// `alias name='value'` lines, guild ones are marked with a comment
+    fn list_aliases(&self) -> Output {
+        let mut aliases: BTreeMap<&str, String> = BTreeMap::new();
+        if let Some(guild) = &self.guild {
+            for (name, value) in &guild.aliases {
+                aliases.insert(name, format!("alias {name}={} # guild", quote(value)));
+            }
+        }
+        for (name, value) in &self.user.aliases {
+            aliases.insert(name, format!("alias {name}={}", quote(value)));
+        }
+
+        lines(aliases.into_values().collect())
+    }
+}
+
+/// `owner`'s definitions in `table`. Functions that don't parse anymore are
+/// logged and left out, their records stay as they are.
+async fn load_scope(
+    db: &surrealdb::Surreal<impl surrealdb::Connection>,
+    table: &str,
+    owner: u64,
+) -> surrealdb::Result<Scope> {
+    let records: Vec<Record> = db
+        .query("SELECT * FROM type::table($table) WHERE owner = $owner")
+        .bind(("table", table))
+        .bind(("owner", owner))
+        .await?
+        .take(0)?;
+
+    let mut scope = Scope::default();
+    for record in records {
+        match record.kind {
+            Kind::Function => match ParseCtx::from_chars(&mut record.source.chars()) {
+                Ok(body) => {
+                    scope.functions.insert(record.name, body);
+                }
+                Err(err) => println!("Error parsing stored function {} {err:?}", record.name),
+            },
+            Kind::Alias => {
+                scope.aliases.insert(record.name, record.source);
+            }
+        }
+    }
+    Ok(scope)
+}
+
+fn record_id(owner: u64, kind: Kind, name: &str) -> String {
+    let kind = match kind {
+        Kind::Function => "function",
+        Kind::Alias => "alias",
+    };
+    format!("{owner}_{kind}_{name}")
+}
+
+/// Single quoted so the value parses back as is, `\\` and `$` still have a
+/// meaning there so they're escaped
+fn quote(value: &str) -> String {
+    let mut quoted = String::from("'");
+    for ch in value.chars() {
+        match ch {
+            '\\' => quoted.push_str("\\\\"),
+            // these have no escape of their own
+            '\'' | '$' => quoted.push_str(&format!("\\u{{{:x}}}", ch as u32)),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// Nothing to print is no output at all, not an empty line
+fn lines(lines: Vec<String>) -> Output {
+    if lines.is_empty() {
+        return Output::default();
+    }
+    EnvironValue::String(lines.join("\n").into()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::parser::ShellArg, *};
+
+    #[test]
+    fn quoted_values_parse_back() {
+        for value in [
+            "echo $x",
+            r"printf a\nb\\",
+            "it's",
+            "'\"${a}$(b)\"'",
+            "",
+            "a  b\n",
+        ] {
+            let quoted = quote(value);
+            let parsed: String = ParseCtx::word_from_chars(&mut quoted.chars())
+                .unwrap_or_else(|err| panic!("{quoted:?} doesn't parse: {err:?}"))
+                .into_iter()
+                .map(|arg| match arg {
+                    ShellArg::Char(ch) => ch,
+                    arg => panic!("{quoted:?} parsed to {arg:?}"),
+                })
+                .collect();
+            assert_eq!(parsed, value, "quoted as {quoted:?}");
+        }
+    }
+}
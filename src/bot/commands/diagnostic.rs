@@ -0,0 +1,145 @@
+//! Positions in the original message and `^~~~` error rendering
+
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+/// Chars `column..column + len` of `line`, lines start at 1 so line `0`
+/// means the position is unknown, as for stored functions or aliases
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, column: usize, len: usize) -> Self {
+        Self { line, column, len }
+    }
+
+    /// From the start of `self` to the end of `other`, only `self` if they
+    /// aren't on the same line
+    pub fn to(self, other: Self) -> Self {
+        if self.line != other.line || other.column < self.column {
+            return self;
+        }
+        Self {
+            len: other.column + other.len - self.column,
+            ..self
+        }
+    }
+}
+
+/// Errors [`Spanned::render`] can show, a code goes as `error[CODE]` and a
+/// hint under the marked line
+pub trait Diagnostic: Display {
+    fn code(&self) -> Option<&'static str> {
+        None
+    }
+    fn hint(&self) -> Option<String> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spanned<E> {
+    pub error: E,
+    pub span: Span,
+}
+
+impl<E> Spanned<E> {
+    pub fn new(error: E, span: Span) -> Self {
+        Self { error, span }
+    }
+
+    pub fn map<F>(self, f: impl FnOnce(E) -> F) -> Spanned<F> {
+        Spanned::new(f(self.error), self.span)
+    }
+}
+
+impl<E: Diagnostic> Spanned<E> {
+    /// The error message, followed by the line it happened on with the span
+    /// marked under it
+    pub fn render(&self, source: &str) -> String {
+        let mut rendered = match self.error.code() {
+            Some(code) => format!("error[{code}]: {}\n", self.error),
+            None => format!("error: {}\n", self.error),
+        };
+
+        let line = self
+            .span
+            .line
+            .checked_sub(1)
+            .and_then(|i| source.lines().nth(i));
+        if let Some(line) = line {
+            let number = self.span.line.to_string();
+            let column = self.span.column.min(line.chars().count());
+            rendered += &format!(
+                "{number} | {line}\n{} | {}^{}\n",
+                " ".repeat(number.len()),
+                " ".repeat(column),
+                "~".repeat(self.span.len.saturating_sub(1)),
+            );
+        }
+        if let Some(hint) = self.error.hint() {
+            rendered += &format!("hint: {hint}\n");
+        }
+
+        rendered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    /// Every file with `code()` impls, new ones need to be added here
+    const SOURCES: &[&str] = &[
+        include_str!("mod.rs"),
+        include_str!("arithmetic.rs"),
+        include_str!("parser.rs"),
+        include_str!("permissions.rs"),
+        include_str!("ratelimit.rs"),
+        include_str!("list/mod.rs"),
+    ];
+    const README: &str = include_str!("../../../README.md");
+
+    /// Codes used outside of tests, the ones tests expect are repeats
+    fn used() -> impl Iterator<Item = &'static str> {
+        SOURCES.iter().flat_map(|source| {
+            let (source, _) = source.split_once("#[cfg(test)]").unwrap_or((source, ""));
+            codes(source, "\"", "\"")
+        })
+    }
+
+    /// `"E123"` string literals, or `| E123 |` table cells
+    fn codes<'a>(text: &'a str, open: &'a str, close: &'a str) -> Vec<&'a str> {
+        text.match_indices(open)
+            .filter_map(|(i, _)| {
+                let code = text.get(i + open.len()..i + open.len() + 4)?;
+                let rest = &text[i + open.len() + 4..];
+                let valid = code.starts_with('E')
+                    && code[1..].bytes().all(|b| b.is_ascii_digit())
+                    && rest.starts_with(close);
+                valid.then_some(code)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn codes_are_unique() {
+        let mut seen = BTreeSet::new();
+        for code in used() {
+            assert!(seen.insert(code), "{code} is used more than once");
+        }
+        assert!(!seen.is_empty(), "no codes found");
+    }
+
+    #[test]
+    fn readme_lists_every_code() {
+        let used: BTreeSet<_> = used().collect();
+        let listed: BTreeSet<_> = codes(README, "| ", " |").into_iter().collect();
+        assert_eq!(used, listed);
+    }
+}
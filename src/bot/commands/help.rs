@@ -0,0 +1,187 @@
+//! `help` and `--help`, rendered from the commands' clap definitions with
+//! the styles Discord's ```ansi code blocks can show
+
+use super::{
+    list::{COMMAND_LIST, COMMAND_MAP},
+    parser::{EnvironValue, Output},
+};
+
+use anstyle::{AnsiColor, Color, Effects, Style};
+use clap::{arg, builder::Styles};
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// Commands the interpreter and [`super::HardcodedExecuter`] handle
+    /// themselves. They parse their own args, these are only for their help.
+    pub static ref BUILTINS: Vec<clap::Command> = [
+        builtin("env", "List exported env variables"),
+        builtin("declare", "Give variables attributes").args([
+            arg!(readonly: -r "Readonly"),
+            arg!(exported: -x "Exported, listed by env"),
+            arg!(secret: -s "Secret, env shows it as ***"),
+            arg!(integer: -i "Integer"),
+            arg!(array: -a "Array"),
+            arg!(map: -A "Map"),
+            arg!([NAME] ... "NAME or NAME=VALUE"),
+        ]),
+        builtin("let", "Define an env variable").arg(arg!(<ASSIGNMENT> "NAME=VALUE")),
+        builtin("array", "Define an array variable from the rest of the args")
+            .args([arg!(<NAME>), arg!([VALUE] ...)]),
+        builtin("map", "Define a map variable from key=value args")
+            .args([arg!(<NAME>), arg!([ENTRY] ... "KEY=VALUE")]),
+        builtin("printargs", "Prints arguments").arg(arg!([ARG] ...)),
+        builtin("true", "Do nothing, successfully"),
+        builtin("false", "Do nothing, unsuccessfully"),
+        builtin("alias", "List or define aliases")
+            .arg(arg!([ALIAS] ... "NAME to print it, NAME=VALUE to define it")),
+        builtin("unalias", "Remove an alias").arg(arg!(<NAME>)),
+        builtin("unfunction", "Remove a function").arg(arg!(<NAME>)),
+        builtin("share", "Share one of your functions or aliases with the server")
+            .arg(arg!(<NAME>)),
+        builtin("unshare", "Remove a shared function or alias").arg(arg!(<NAME>)),
+    ]
+    .into();
+}
+
+fn builtin(name: &'static str, about: &'static str) -> clap::Command {
+    clap::Command::new(name).about(about).styles(styles())
+}
+
+/// The help of the builtin in `args`, if it's called with only `-h` or
+/// `--help`
+pub fn builtin_help(args: &[EnvironValue]) -> Option<Output> {
+    let [name, flag] = args else {
+        return None;
+    };
+    let (name, flag) = (name.clone().as_string()?, flag.clone().as_string()?);
+    if flag != "-h" && flag != "--help" {
+        return None;
+    }
+
+    let builtin = BUILTINS.iter().find(|builtin| builtin.get_name() == name)?;
+    let help = builtin.clone().render_help().ansi().to_string();
+    Some(EnvironValue::String(help.into()).into())
+}
+
+/// Discord's basic colors in SGR order, it has no bright ones
+const COLORS: [AnsiColor; 8] = [
+    AnsiColor::Black,
+    AnsiColor::Red,
+    AnsiColor::Green,
+    AnsiColor::Yellow,
+    AnsiColor::Blue,
+    AnsiColor::Magenta,
+    AnsiColor::Cyan,
+    AnsiColor::White,
+];
+
+/// The closest basic color, each channel is either on or off
+fn from_rgb(r: u8, g: u8, b: u8) -> AnsiColor {
+    let index = usize::from(r >= 128) | usize::from(g >= 128) << 1 | usize::from(b >= 128) << 2;
+    COLORS[index]
+}
+
+/// The xterm palette past the first 16, a 6x6x6 cube then a gray ramp
+fn rgb_256(index: u8) -> (u8, u8, u8) {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    match index {
+        16..=231 => {
+            let index = usize::from(index - 16);
+            (LEVELS[index / 36], LEVELS[index / 6 % 6], LEVELS[index % 6])
+        }
+        _ => {
+            let gray = 8 + 10 * index.saturating_sub(232);
+            (gray, gray, gray)
+        }
+    }
+}
+
+fn discord_color(color: Color) -> Color {
+    let ansi = match color {
+        Color::Ansi(ansi) => ansi,
+        Color::Ansi256(color) => color.into_ansi().unwrap_or_else(|| {
+            let (r, g, b) = rgb_256(color.index());
+            from_rgb(r, g, b)
+        }),
+        Color::Rgb(rgb) => from_rgb(rgb.r(), rgb.g(), rgb.b()),
+    };
+    Color::Ansi(ansi.bright(false))
+}
+
+/// Only the foreground, bold and underline are kept
+fn discord_style(style: &Style) -> Style {
+    let effects = style.get_effects();
+    Style::new()
+        .fg_color(style.get_fg_color().map(discord_color))
+        .effects(
+            Effects::new()
+                .set(Effects::BOLD, effects.contains(Effects::BOLD))
+                .set(Effects::UNDERLINE, effects.contains(Effects::UNDERLINE)),
+        )
+}
+
+/// The bot's own clap styles, as close as Discord can show them
+pub fn styles() -> Styles {
+    let styles = crate::args::get_clap_styles();
+    Styles::styled()
+        .header(discord_style(styles.get_header()))
+        .error(discord_style(styles.get_error()))
+        .usage(discord_style(styles.get_usage()))
+        .literal(discord_style(styles.get_literal()))
+        .placeholder(discord_style(styles.get_placeholder()))
+        .valid(discord_style(styles.get_valid()))
+        .invalid(discord_style(styles.get_invalid()))
+}
+
+/// Every command with its description, as `help` shows them
+pub fn overview() -> String {
+    let commands: Vec<_> = COMMAND_LIST
+        .iter()
+        .map(|cmd| {
+            let about = cmd.clap().get_about().map(ToString::to_string);
+            (cmd.name(), about.unwrap_or_default())
+        })
+        .collect();
+    let builtins: Vec<_> = BUILTINS
+        .iter()
+        .map(|builtin| {
+            let about = builtin.get_about().map(ToString::to_string);
+            (builtin.get_name(), about.unwrap_or_default())
+        })
+        .collect();
+
+    let styles = styles();
+    let (header, literal) = (styles.get_header(), styles.get_literal());
+    let width = commands
+        .iter()
+        .map(|(name, _)| name.len())
+        .chain(builtins.iter().map(|(name, _)| name.len()))
+        .max()
+        .unwrap_or_default();
+
+    let mut help = String::new();
+    for (title, list) in [("Commands:", commands), ("Builtins:", builtins)] {
+        help += &format!("{header}{title}{header:#}\n");
+        for (name, about) in list {
+            let pad = width - name.len();
+            help += &format!("  {literal}{name}{literal:#}{:pad$}  {about}\n", "");
+        }
+        help += "\n";
+    }
+    help += &format!(
+        "See {literal}help COMMAND{literal:#} or {literal}COMMAND --help{literal:#} for more\n"
+    );
+    help
+}
+
+/// `name`'s full help, `None` if there's no such command
+pub fn command(name: &str) -> Option<String> {
+    let mut cmd = match COMMAND_MAP.get(name) {
+        Some(cmd) => cmd.clap(),
+        None => BUILTINS
+            .iter()
+            .find(|builtin| builtin.get_name() == name)?
+            .clone(),
+    };
+    Some(cmd.render_help().ansi().to_string())
+}
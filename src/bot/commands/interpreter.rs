@@ -0,0 +1,1090 @@
+//! Runs parsed [`CommandList`]s, driving an [`Executer`] for every simple
+//! command
+
+use crate::config::Limits;
+
+use super::{
+    definitions::Definitions,
+    diagnostic::{Diagnostic, Span, Spanned},
+    parser::{
+        Command, CommandList, Environ, EnvironValue, ExecuteError, Executer, Limit, Operator,
+        Output, ParamExpansion, ParseCtx, Pipeline, Redirect, ShellArg, ShellArgs,
+    },
+};
+
+use std::{borrow::Cow, ffi::OsString, fmt::Display, mem::take, os::unix::ffi::OsStringExt};
+
+use futures::future::BoxFuture;
+
+/// State of a single message's execution
+pub struct Interpreter {
+    pub definitions: Definitions,
+    limits: Limits,
+    /// Shared by commands, loop iterations and function calls
+    commands_left: usize,
+    /// Once a limit is hit nothing else runs
+    limit_hit: Option<Limit>,
+    /// Lists being ran inside each other
+    depth: usize,
+    /// Positional parameters (`$1`, `$2`...) set by the current function
+    positional: usize,
+    /// Aliases being expanded, they don't expand again inside themselves
+    expanding: Vec<String>,
+    /// The message, errors show the line they happened on
+    source: String,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self {
+            definitions: Definitions::default(),
+            limits: Limits::default(),
+            commands_left: Limits::default().commands,
+            limit_hit: None,
+            depth: 0,
+            positional: 0,
+            expanding: vec![],
+            source: String::new(),
+        }
+    }
+}
+
+/// Stdout and stderr of commands ran one after the other
+#[derive(Default)]
+struct Outputs {
+    stdouts: Vec<EnvironValue>,
+    stderr: OsString,
+}
+
+impl Outputs {
+    /// Returns the status of the pushed output
+    fn push(&mut self, output: Output) -> u8 {
+        self.stdouts.push(output.stdout);
+        self.stderr.push(output.stderr);
+        output.status
+    }
+
+    /// Stdouts get joined with newlines, a single one keeps its type
+    fn finish<E: Display>(mut self, status: u8) -> Output {
+        self.stdouts.retain(|stdout| *stdout != EnvironValue::None);
+
+        let stdout = if self.stdouts.len() <= 1 {
+            self.stdouts.pop().unwrap_or(EnvironValue::None)
+        } else {
+            let Some(stdouts) = self
+                .stdouts
+                .into_iter()
+                .map(EnvironValue::as_string)
+                .collect::<Option<Vec<_>>>()
+            else {
+                let mut output = Output::from(ExecuteError::<E>::UnserializableValue);
+                self.stderr.push(output.stderr);
+                output.stderr = self.stderr;
+                return output;
+            };
+            EnvironValue::String(stdouts.join(&OsString::from("\n")))
+        };
+
+        Output {
+            status,
+            stdout,
+            stderr: self.stderr,
+        }
+    }
+}
+
+impl Interpreter {
+    pub fn new(definitions: Definitions, source: String, limits: Limits) -> Self {
+        Self {
+            definitions,
+            source,
+            limits,
+            commands_left: limits.commands,
+            ..Default::default()
+        }
+    }
+
+    /// The limit that stopped the message, if any
+    pub fn limit_hit(&self) -> Option<Limit> {
+        self.limit_hit
+    }
+
+    fn exceed<E>(&mut self, limit: Limit) -> ExecuteError<E> {
+        self.limit_hit = Some(limit);
+        ExecuteError::LimitExceeded(limit)
+    }
+
+    /// Failed command with the rendered error as its stderr
+    fn failure<E: Diagnostic>(&self, err: Spanned<ExecuteError<E>>) -> Output {
+        Output::failure(1, err.render(&self.source))
+    }
+
+    pub async fn run<'a, E: Diagnostic + Send>(
+        &mut self,
+        list: CommandList,
+        environ: &mut (impl Environ<'a> + Send),
+        executer: &mut (impl Executer<E> + Send),
+    ) -> Output {
+        self.list(list, EnvironValue::None, environ, executer).await
+    }
+
+    /// Runs a list inside the current one, for blocks, function calls,
+    /// aliases and subshells. They all take stack space, so this is where
+    /// [`Limits::run_depth`] is checked.
+    async fn nested<'a, E: Diagnostic + Send>(
+        &mut self,
+        list: CommandList,
+        input: EnvironValue,
+        environ: &mut (impl Environ<'a> + Send),
+        executer: &mut (impl Executer<E> + Send),
+    ) -> Result<Output, ExecuteError<E>> {
+        if self.depth >= self.limits.run_depth {
+            do yeet self.exceed(Limit::RunDepth(self.limits.run_depth));
+        }
+        self.depth += 1;
+        let output = self.list(list, input, environ, executer).await;
+        self.depth -= 1;
+        Ok(output)
+    }
+
+    /// [`Interpreter::nested`] for blocks, its error is a failed command at
+    /// `span`
+    async fn block<'a, E: Diagnostic + Send>(
+        &mut self,
+        list: CommandList,
+        span: Span,
+        environ: &mut (impl Environ<'a> + Send),
+        executer: &mut (impl Executer<E> + Send),
+    ) -> Output {
+        self.nested(list, EnvironValue::None, environ, executer)
+            .await
+            .unwrap_or_else(|err| self.failure(Spanned::new(err, span)))
+    }
+
+    /// `&&` and `||` run their pipeline depending on the last status, which
+    /// is also stored in `$?`. Only the first pipeline gets the `input`.
+    fn list<'s, 'a, E: Diagnostic + Send + 's>(
+        &'s mut self,
+        list: CommandList,
+        mut input: EnvironValue,
+        environ: &'s mut (impl Environ<'a> + Send),
+        executer: &'s mut (impl Executer<E> + Send),
+    ) -> BoxFuture<'s, Output> {
+        Box::pin(async move {
+            let mut outputs = Outputs::default();
+            let mut status = 0;
+
+            for (op, pipeline) in list.0 {
+                match op {
+                    _ if self.limit_hit.is_some() => break,
+                    Operator::And if status != 0 => continue,
+                    Operator::Or if status == 0 => continue,
+                    _ => {}
+                };
+
+                let output = self
+                    .pipeline(pipeline, take(&mut input), &mut *environ, executer)
+                    .await;
+                // `?` can't be declared, so this can't fail
+                let _ = environ.set(
+                    String::from("?"),
+                    EnvironValue::UNumber(output.status.into()),
+                );
+                status = outputs.push(output);
+            }
+
+            outputs.finish::<E>(status)
+        })
+    }
+
+    /// The status is the last command's one, stderr is shared by all of them
+    async fn pipeline<'a, E: Diagnostic + Send>(
+        &mut self,
+        pipeline: Pipeline,
+        input: EnvironValue,
+        environ: &mut (impl Environ<'a> + Send),
+        executer: &mut (impl Executer<E> + Send),
+    ) -> Output {
+        let mut output = Output::from(input);
+        let mut stderr = OsString::new();
+
+        for command in pipeline.0 {
+            if self.limit_hit.is_some() {
+                break;
+            }
+            let input = take(&mut output.stdout);
+            output = self
+                .command(command, input, &mut *environ, executer)
+                .await
+                .unwrap_or_else(|err| self.failure(err));
+            stderr.push(take(&mut output.stderr));
+        }
+
+        output.stderr = stderr;
+        output
+    }
+
+    /// Takes one from the [`Limits::commands`] budget, for each command, loop
+    /// iteration and function call
+    async fn tick<E>(&mut self) -> Result<(), ExecuteError<E>> {
+        let Some(commands_left) = self.commands_left.checked_sub(1) else {
+            do yeet self.exceed(Limit::Commands(self.limits.commands));
+        };
+        self.commands_left = commands_left;
+        // nothing else may await in loops of builtins, this lets the
+        // message's timeout cancel them
+        tokio::task::yield_now().await;
+        Ok(())
+    }
+
+    /// Compound commands ignore their input
+    async fn command<'a, E: Diagnostic + Send>(
+        &mut self,
+        command: Command,
+        input: EnvironValue,
+        environ: &mut (impl Environ<'a> + Send),
+        executer: &mut (impl Executer<E> + Send),
+    ) -> Result<Output, Spanned<ExecuteError<E>>> {
+        match command {
+            Command::Simple(args) => self.simple(args, input, environ, executer).await,
+            Command::If {
+                branches,
+                otherwise,
+                span,
+            } => {
+                let mut outputs = Outputs::default();
+
+                for (cond, body) in branches {
+                    if outputs.push(self.block(cond, span, &mut *environ, executer).await) == 0 {
+                        let status =
+                            outputs.push(self.block(body, span, &mut *environ, executer).await);
+                        return Ok(outputs.finish::<E>(status));
+                    }
+                }
+
+                let status = match otherwise {
+                    Some(otherwise) => {
+                        outputs.push(self.block(otherwise, span, environ, executer).await)
+                    }
+                    None => 0,
+                };
+                Ok(outputs.finish::<E>(status))
+            }
+            Command::For {
+                var,
+                words,
+                body,
+                span,
+            } => {
+                let mut outputs = Outputs::default();
+                let mut values = vec![];
+                for word in words {
+                    values.extend(
+                        self.words(word, &mut outputs.stderr, &mut *environ, executer)
+                            .await
+                            .map_err(|err| Spanned::new(err, span))?,
+                    );
+                }
+
+                let mut status = 0;
+                for value in values {
+                    if let Err(err) = self.tick::<E>().await {
+                        status = outputs.push(self.failure(Spanned::new(err, span)));
+                        break;
+                    }
+
+                    if let Err(err) = environ.set(var.clone(), value) {
+                        let err = Spanned::new(ExecuteError::<E>::Environ(err), span);
+                        status = outputs.push(self.failure(err));
+                        break;
+                    }
+                    status = outputs.push(
+                        self.block(body.clone(), span, &mut *environ, executer)
+                            .await,
+                    );
+                    if self.limit_hit.is_some() {
+                        break;
+                    }
+                }
+                Ok(outputs.finish::<E>(status))
+            }
+            Command::While {
+                cond,
+                body,
+                until,
+                span,
+            } => {
+                let mut outputs = Outputs::default();
+
+                let mut status = 0;
+                loop {
+                    if let Err(err) = self.tick::<E>().await {
+                        status = outputs.push(self.failure(Spanned::new(err, span)));
+                        break;
+                    }
+
+                    let cond_status = outputs.push(
+                        self.block(cond.clone(), span, &mut *environ, executer)
+                            .await,
+                    );
+                    if (cond_status == 0) == until || self.limit_hit.is_some() {
+                        break;
+                    }
+                    status = outputs.push(
+                        self.block(body.clone(), span, &mut *environ, executer)
+                            .await,
+                    );
+                    if self.limit_hit.is_some() {
+                        break;
+                    }
+                }
+                Ok(outputs.finish::<E>(status))
+            }
+            Command::Case { word, items, span } => {
+                let mut stderr = OsString::new();
+                let at_word = |err| Spanned::new(err, span);
+                let word = self
+                    .arg(word, &mut stderr, &mut *environ, executer)
+                    .await
+                    .map_err(at_word)?
+                    .into_bytes();
+
+                for (patterns, body) in items {
+                    for pattern in patterns {
+                        let pattern = self
+                            .arg(pattern, &mut stderr, &mut *environ, executer)
+                            .await
+                            .map_err(at_word)?
+                            .into_bytes();
+
+                        if glob_match(&pattern, &word) {
+                            let mut output = self.block(body, span, environ, executer).await;
+                            stderr.push(output.stderr);
+                            output.stderr = stderr;
+                            return Ok(output);
+                        }
+                    }
+                }
+
+                Ok(Output {
+                    stderr,
+                    ..Default::default()
+                })
+            }
+            Command::Function { name, body } => {
+                self.definitions.define_function(name, body);
+                Ok(Output::default())
+            }
+        }
+    }
+
+    /// Runs a function with `args` as positional parameters, restoring the
+    /// caller's ones afterwards
+    async fn call<'a, E: Diagnostic + Send>(
+        &mut self,
+        body: CommandList,
+        args: Vec<EnvironValue>,
+        input: EnvironValue,
+        environ: &mut (impl Environ<'a> + Send),
+        executer: &mut (impl Executer<E> + Send),
+    ) -> Result<Output, ExecuteError<E>> {
+        self.tick().await?;
+
+        let count = args.len() - 1;
+        // the caller's extra parameters are hidden too
+        let names: Vec<String> = (1..=count.max(self.positional))
+            .map(|n| n.to_string())
+            .collect();
+        let saved: Vec<_> = names
+            .iter()
+            .map(|name| environ.get(name).map(Cow::into_owned))
+            .collect();
+
+        // positional parameters can't be declared, setting them can't fail
+        let all = environ
+            .set(String::from("@"), EnvironValue::Array(args[1..].to_vec()))
+            .ok()
+            .flatten();
+        let mut args = args.into_iter().skip(1);
+        for name in &names {
+            let _ = environ.set(name.clone(), args.next().unwrap_or_default());
+        }
+
+        let positional = std::mem::replace(&mut self.positional, count);
+        let output = self.nested(body, input, &mut *environ, executer).await;
+        self.positional = positional;
+
+        for (name, value) in names.into_iter().zip(saved) {
+            restore(environ, name, value);
+        }
+        restore(environ, String::from("@"), all);
+        output
+    }
+
+    /// Replaces the alias in the command name by its parsed value, the rest
+    /// of the words and redirections go to its last command
+    fn expand_alias<E>(
+        &self,
+        args: &mut ShellArgs,
+    ) -> Result<Option<(String, CommandList)>, ExecuteError<E>> {
+        let Some(name) = args.args.first().and_then(|word| ShellArg::literal(word)) else {
+            return Ok(None);
+        };
+        let Some(value) = self
+            .definitions
+            .alias(&name)
+            .filter(|_| !self.expanding.contains(&name))
+        else {
+            return Ok(None);
+        };
+
+        let mut list = ParseCtx::from_chars(&mut value.chars())
+            .map_err(|_| ExecuteError::InvalidAlias(name.clone()))?;
+
+        let ShellArgs {
+            mut args,
+            redirects,
+            mut spans,
+            ..
+        } = take(args);
+        args.remove(0);
+        if !spans.is_empty() {
+            spans.remove(0);
+        }
+        if !args.is_empty() || !redirects.is_empty() {
+            let Some((_, Pipeline(commands))) = list.0.last_mut() else {
+                do yeet ExecuteError::InvalidAlias(name);
+            };
+            let Some(Command::Simple(last)) = commands.last_mut() else {
+                do yeet ExecuteError::InvalidAlias(name);
+            };
+            last.args.extend(args);
+            last.redirects.extend(redirects);
+            last.spans.extend(spans);
+        }
+
+        Ok(Some((name, list)))
+    }
+
+    /// A command with only redirections just outputs its input, so
+    /// `< A > B` copies `A` into `B`
+    async fn simple<'a, E: Diagnostic + Send>(
+        &mut self,
+        mut args: ShellArgs,
+        mut input: EnvironValue,
+        environ: &mut (impl Environ<'a> + Send),
+        executer: &mut (impl Executer<E> + Send),
+    ) -> Result<Output, Spanned<ExecuteError<E>>> {
+        let span = args.span;
+        let at_command = |err| Spanned::new(err, span);
+
+        self.tick().await.map_err(at_command)?;
+
+        if let Some((name, list)) = self.expand_alias(&mut args).map_err(at_command)? {
+            self.expanding.push(name);
+            let output = self.nested(list, input, environ, executer).await;
+            self.expanding.pop();
+            return output.map_err(at_command);
+        }
+
+        // subshells' stderr gets attached to this command's
+        let mut stderr = OsString::new();
+        let spans = args.spans;
+        let mut arg_list = vec![];
+        for (i, arg) in args.args.into_iter().enumerate() {
+            arg_list.extend(
+                self.words(arg, &mut stderr, &mut *environ, executer)
+                    .await
+                    .map_err(|err| Spanned::new(err, spans.get(i).copied().unwrap_or(span)))?,
+            );
+        }
+        if arg_list.len() > self.limits.expanded_args {
+            let err = self.exceed(Limit::ExpandedArgs(self.limits.expanded_args));
+            return Err(at_command(err));
+        }
+
+        let mut outputs = vec![];
+        for redirect in args.redirects {
+            let (Redirect::Input(target) | Redirect::Output(target) | Redirect::Append(target)) =
+                &redirect;
+            let target = self
+                .arg(target.clone(), &mut stderr, &mut *environ, executer)
+                .await
+                .map_err(at_command)?
+                .as_string()
+                .ok_or(at_command(ExecuteError::UnserializableValue))?
+                .into_string()
+                .map_err(|_| at_command(ExecuteError::InvalidEnvName))?;
+
+            match redirect {
+                Redirect::Input(_) => {
+                    input = environ
+                        .get(&target)
+                        .ok_or_else(|| at_command(ExecuteError::NoSuchEnv(target.clone())))?
+                        .into_owned()
+                }
+                Redirect::Output(_) => outputs.push((target, false)),
+                Redirect::Append(_) => outputs.push((target, true)),
+            }
+        }
+
+        // functions shadow builtins
+        let name = match arg_list.first() {
+            Some(EnvironValue::String(name)) => name.to_str(),
+            _ => None,
+        };
+        let function = name
+            .and_then(|name| self.definitions.function(name))
+            .cloned();
+
+        // the executer checks what it runs itself. Functions are checked
+        // under their name, so one shadowing a command follows its rules.
+        if let Some(name) =
+            name.filter(|name| function.is_some() || Definitions::BUILTINS.contains(name))
+        {
+            executer
+                .check(name)
+                .map_err(|err| at_command(ExecuteError::ExecuterError(err)))?;
+        }
+
+        let mut output = if arg_list.is_empty() {
+            Output::from(input)
+        } else if let Some(body) = function {
+            self.call(body, arg_list, input, &mut *environ, executer)
+                .await
+                .map_err(at_command)?
+        } else if let Some(output) = self.definitions.builtin(&arg_list) {
+            output
+        } else {
+            executer
+                .execute(arg_list, input, environ)
+                .await
+                .map_err(|err| at_command(ExecuteError::ExecuterError(err)))?
+        };
+        stderr.push(output.stderr);
+        output.stderr = stderr;
+        self.check_size(&output.stdout).map_err(at_command)?;
+
+        // like bash, every `>` target gets truncated but only the last one
+        // gets the output
+        if let Some((last, append)) = outputs.pop() {
+            for (target, append) in outputs {
+                if !append {
+                    environ
+                        .set(target, EnvironValue::None)
+                        .map_err(|err| at_command(ExecuteError::Environ(err)))?;
+                }
+            }
+
+            let stdout = take(&mut output.stdout);
+            if append {
+                environ
+                    .append(last.clone(), stdout)
+                    .map_err(|err| at_command(ExecuteError::Environ(err)))?;
+                if let Some(value) = environ.get(&last) {
+                    self.check_size(&value).map_err(at_command)?;
+                }
+            } else {
+                environ
+                    .set(last, stdout)
+                    .map_err(|err| at_command(ExecuteError::Environ(err)))?;
+            }
+        }
+
+        Ok(output)
+    }
+
+    /// Like [`Interpreter::arg`], but a word that is only a
+    /// [`ParamExpansion::Splat`] becomes one value per element
+    async fn words<'a, E: Diagnostic + Send>(
+        &mut self,
+        arg: Vec<ShellArg>,
+        stderr: &mut OsString,
+        environ: &mut (impl Environ<'a> + Send),
+        executer: &mut (impl Executer<E> + Send),
+    ) -> Result<Vec<EnvironValue>, ExecuteError<E>> {
+        let splat = matches!(
+            arg.as_slice(),
+            [ShellArg::Parameter(ParamExpansion::Splat(_))]
+        );
+        let value = self.arg(arg, stderr, environ, executer).await?;
+        self.check_size(&value)?;
+        Ok(match value {
+            EnvironValue::Array(items) if splat => items,
+            value => vec![value],
+        })
+    }
+
+    /// Values past [`Limits::output_bytes`] fail the command that made them
+    fn check_size<E: Display>(&mut self, value: &EnvironValue) -> Result<(), ExecuteError<E>> {
+        if value.size() > self.limits.output_bytes {
+            Err(self.exceed(Limit::OutputBytes(self.limits.output_bytes)))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn arg<'a, E: Diagnostic + Send>(
+        &mut self,
+        mut arg: Vec<ShellArg>,
+        stderr: &mut OsString,
+        environ: &mut (impl Environ<'a> + Send),
+        executer: &mut (impl Executer<E> + Send),
+    ) -> Result<EnvironValue, ExecuteError<E>> {
+        // lone expansions keep their type
+        if arg.len() == 1 {
+            match arg.remove(0) {
+                ShellArg::EnvVar(value) => {
+                    return Ok(environ
+                        .get(&value)
+                        .ok_or(ExecuteError::NoSuchEnv(value.clone()))?
+                        .into_owned());
+                }
+                ShellArg::Subshell(list) => {
+                    let output = self
+                        .nested(list, EnvironValue::None, environ, executer)
+                        .await?;
+                    stderr.push(output.stderr);
+                    return Ok(output.stdout);
+                }
+                ShellArg::Parameter(param) => {
+                    return self.param(param, stderr, environ, executer).await;
+                }
+                ShellArg::Arithmetic(expr) => {
+                    return Ok(EnvironValue::Number(
+                        expr.eval(environ).map_err(ExecuteError::Arithmetic)?,
+                    ));
+                }
+                component => arg.push(component),
+            };
+        }
+
+        let mut arg_string = OsString::new();
+        for component in arg {
+            match component {
+                ShellArg::Byte(byte) => arg_string.push(OsString::from_vec(vec![byte])),
+                ShellArg::Char(ch) => arg_string.push(ch.to_string()),
+                ShellArg::RawString(rstring) => arg_string.push(rstring),
+                ShellArg::String(string) => arg_string.push(string),
+                ShellArg::EnvVar(env_ref) => arg_string.push(
+                    environ
+                        .get(&env_ref)
+                        .ok_or_else(|| ExecuteError::NoSuchEnv(env_ref.clone()))?
+                        .into_owned()
+                        .as_string()
+                        .ok_or(ExecuteError::UnserializableValue)?,
+                ),
+                ShellArg::Subshell(list) => {
+                    let output = self
+                        .nested(list, EnvironValue::None, &mut *environ, executer)
+                        .await?;
+                    stderr.push(output.stderr);
+                    arg_string.push(
+                        output
+                            .stdout
+                            .as_string()
+                            .ok_or(ExecuteError::UnserializableValue)?,
+                    )
+                }
+                ShellArg::Parameter(param) => arg_string.push(
+                    self.param(param, stderr, &mut *environ, executer)
+                        .await?
+                        .as_string()
+                        .ok_or(ExecuteError::UnserializableValue)?,
+                ),
+                ShellArg::Arithmetic(expr) => arg_string.push(
+                    expr.eval(environ)
+                        .map_err(ExecuteError::Arithmetic)?
+                        .to_string(),
+                ),
+            };
+        }
+        Ok(EnvironValue::String(arg_string))
+    }
+
+    /// Unlike `$NAME`, missing variables are treated as empty here
+    fn param<'s, 'a, E: Diagnostic + Send + 's>(
+        &'s mut self,
+        param: ParamExpansion,
+        stderr: &'s mut OsString,
+        environ: &'s mut (impl Environ<'a> + Send),
+        executer: &'s mut (impl Executer<E> + Send),
+    ) -> BoxFuture<'s, Result<EnvironValue, ExecuteError<E>>> {
+        Box::pin(async move {
+            match param {
+                ParamExpansion::Default { name, word, assign } => {
+                    if let Some(value) = environ.get(&name).filter(|value| !value.is_empty()) {
+                        return Ok(value.into_owned());
+                    }
+
+                    let value = self.arg(word, stderr, &mut *environ, executer).await?;
+                    if assign {
+                        environ
+                            .set(name, value.clone())
+                            .map_err(ExecuteError::Environ)?;
+                    }
+                    Ok(value)
+                }
+                ParamExpansion::Length(name) => {
+                    Ok(EnvironValue::UNumber(match environ.get(&name).as_deref() {
+                        Some(EnvironValue::Blob(blob)) => blob.len() as u128,
+                        Some(EnvironValue::Array(items)) => items.len() as u128,
+                        Some(EnvironValue::Map(entries)) => entries.len() as u128,
+                        Some(value) => value
+                            .clone()
+                            .as_string()
+                            .ok_or(ExecuteError::UnserializableValue)?
+                            .to_string_lossy()
+                            .chars()
+                            .count() as u128,
+                        None => 0,
+                    }))
+                }
+                ParamExpansion::Substring {
+                    name,
+                    offset,
+                    length,
+                } => {
+                    let slice_bounds = |len: usize| {
+                        let len = len as i64;
+                        let start = if offset < 0 { len + offset } else { offset }.clamp(0, len);
+                        let end = match length {
+                            None => len,
+                            Some(length) if length < 0 => len + length,
+                            Some(length) => start.saturating_add(length),
+                        }
+                        .clamp(start, len);
+                        start as usize..end as usize
+                    };
+
+                    match environ.get(&name).map(Cow::into_owned).unwrap_or_default() {
+                        EnvironValue::Blob(blob) => {
+                            Ok(EnvironValue::Blob(blob[slice_bounds(blob.len())].to_vec()))
+                        }
+                        EnvironValue::Array(items) => Ok(EnvironValue::Array(
+                            items[slice_bounds(items.len())].to_vec(),
+                        )),
+                        value => {
+                            let value =
+                                value.as_string().ok_or(ExecuteError::UnserializableValue)?;
+                            let value = value.to_string_lossy();
+                            let chars: Vec<char> = value.chars().collect();
+                            Ok(EnvironValue::String(OsString::from(
+                                chars[slice_bounds(chars.len())].iter().collect::<String>(),
+                            )))
+                        }
+                    }
+                }
+                ParamExpansion::Replace {
+                    name,
+                    pattern,
+                    replacement,
+                    all,
+                } => {
+                    let pattern = self
+                        .arg(pattern, stderr, &mut *environ, executer)
+                        .await?
+                        .into_bytes();
+                    let replacement = self
+                        .arg(replacement, stderr, &mut *environ, executer)
+                        .await?
+                        .into_bytes();
+                    let value = environ.get(&name).map(Cow::into_owned).unwrap_or_default();
+                    let is_blob = matches!(value, EnvironValue::Blob(_));
+                    let mut haystack = value.into_bytes().into_iter();
+
+                    let mut replaced = vec![];
+                    let mut replacing = !pattern.is_empty();
+                    while haystack.len() > 0 {
+                        if replacing && haystack.as_slice().starts_with(&pattern) {
+                            replaced.extend_from_slice(&replacement);
+                            haystack.nth(pattern.len() - 1);
+                            replacing = all;
+                        } else {
+                            replaced.extend(haystack.next());
+                        }
+                    }
+
+                    Ok(if is_blob {
+                        EnvironValue::Blob(replaced)
+                    } else {
+                        EnvironValue::String(OsString::from_vec(replaced))
+                    })
+                }
+                ParamExpansion::Index { name, index } => {
+                    let index = self
+                        .arg(index, stderr, &mut *environ, executer)
+                        .await?
+                        .as_string()
+                        .ok_or(ExecuteError::UnserializableValue)?;
+                    let index = index.to_string_lossy();
+
+                    Ok(match environ.get(&name).as_deref() {
+                        Some(EnvironValue::Map(entries)) => entries.get(index.as_ref()).cloned(),
+                        Some(EnvironValue::Array(items)) => index
+                            .trim()
+                            .parse::<i64>()
+                            .ok()
+                            .and_then(|i| {
+                                let i = if i < 0 { items.len() as i64 + i } else { i };
+                                items.get(usize::try_from(i).ok()?)
+                            })
+                            .cloned(),
+                        // like bash, a scalar is an array of one
+                        Some(value) if index.trim() == "0" => Some(value.clone()),
+                        _ => None,
+                    }
+                    .unwrap_or_default())
+                }
+                ParamExpansion::Splat(name) => {
+                    Ok(EnvironValue::Array(match environ.get(&name).as_deref() {
+                        Some(EnvironValue::Array(items)) => items.clone(),
+                        Some(EnvironValue::Map(entries)) => entries.values().cloned().collect(),
+                        Some(EnvironValue::None) | None => vec![],
+                        Some(value) => vec![value.clone()],
+                    }))
+                }
+            }
+        })
+    }
+}
+
+/// Sets a positional parameter back to `value`, or removes it if there
+/// wasn't one. They can't be declared, so this can't fail.
+fn restore<'a>(environ: &mut impl Environ<'a>, key: String, value: Option<EnvironValue>) {
+    let _ = match value {
+        Some(value) => environ.set(key, value),
+        None => environ.remove(&key),
+    };
+}
+
+/// `*` and `?` wildcards, as used by `case` patterns
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // where to resume after the last `*` if the current attempt fails
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => {
+                let Some((star_p, star_t)) = backtrack else {
+                    return false;
+                };
+                p = star_p;
+                t = star_t + 1;
+                backtrack = Some((star_p, t));
+            }
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{parser::MsgParser, DefaultEnviron, HardcodedExecuter, HardcodedExecuterError},
+        *,
+    };
+
+    /// `echo` prints its args and `check` fails on `0`, everything else
+    /// goes to the shell builtins. `denied` and `unfunction` are denied.
+    struct Stub;
+
+    impl Executer<HardcodedExecuterError> for Stub {
+        async fn execute<'a>(
+            &mut self,
+            args: Vec<EnvironValue>,
+            input: EnvironValue,
+            env: &mut (impl Environ<'a> + Send),
+        ) -> Result<Output, HardcodedExecuterError> {
+            let words: Vec<String> = args
+                .iter()
+                .map(|arg| arg.clone().as_string().unwrap().into_string().unwrap())
+                .collect();
+            match words[0].as_str() {
+                "echo" => Ok(EnvironValue::String(words[1..].join(" ").into()).into()),
+                "check" if words[1] == "0" => Ok(Output::failure(1, "")),
+                "check" => Ok(Output::default()),
+                name => {
+                    self.check(name)?;
+                    HardcodedExecuter.execute(args, input, env).await
+                }
+            }
+        }
+
+        fn check(&self, name: &str) -> Result<(), HardcodedExecuterError> {
+            match name {
+                "denied" | "unfunction" => Err(HardcodedExecuterError::UnknownCommand(name.into())),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    async fn run(script: &str) -> Output {
+        run_limited(script, Limits::default()).await.0
+    }
+
+    /// Ran as a message, `!script`
+    async fn run_limited(script: &str, limits: Limits) -> (Output, Option<Limit>) {
+        let msg = format!("!{script}");
+        let [list] = MsgParser::new("!", &msg)
+            .parse()
+            .unwrap_or_else(|err| panic!("{script:?} doesn't parse: {err:?}"))
+            .try_into()
+            .unwrap();
+        let mut interpreter = Interpreter::new(Definitions::default(), msg, limits);
+        let output = interpreter
+            .run(list, &mut DefaultEnviron::default(), &mut Stub)
+            .await;
+        (output, interpreter.limit_hit())
+    }
+
+    async fn stdout(script: &str) -> String {
+        let output = run(script).await;
+        output.stdout.as_string().unwrap().into_string().unwrap()
+    }
+
+    #[tokio::test]
+    async fn status() {
+        assert_eq!(stdout("false; echo $?").await, "1");
+        assert_eq!(stdout("true && echo a || echo b").await, "a");
+        assert_eq!(stdout("false && echo a || echo b").await, "b");
+        assert_eq!(run("true; false").await.status, 1);
+    }
+
+    #[tokio::test]
+    async fn control_flow() {
+        let script = "if false; then echo a; elif true; then echo b; else echo c; fi";
+        assert_eq!(stdout(script).await, "b");
+        assert_eq!(stdout("if false; then echo a; fi").await, "");
+        assert_eq!(stdout("for x in a b c; do echo $x; done").await, "a\nb\nc");
+
+        let script = "let i=0; while check $((i < 3)); do echo $i; let i=$((i + 1)); done";
+        assert_eq!(stdout(script).await, "0\n1\n2");
+        let script = "let i=0; until check $((i >= 2)); do echo $i; let i=$((i + 1)); done";
+        assert_eq!(stdout(script).await, "0\n1");
+    }
+
+    #[tokio::test]
+    async fn case() {
+        let script = "case main.rs in *.txt) echo text;; m?in.* | x) echo rust;; *) echo any; esac";
+        assert_eq!(stdout(script).await, "rust");
+        assert_eq!(stdout("case a in b) echo b; esac").await, "");
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"a*b*c", b"aXbYbZc"));
+        assert!(glob_match(b"?", b"x"));
+        assert!(!glob_match(b"?", b""));
+        assert!(!glob_match(b"a*b", b"aXbY"));
+    }
+
+    #[tokio::test]
+    async fn functions() {
+        let script = "function greet { echo hi $1 $2; }; greet a b; greet c d";
+        assert_eq!(stdout(script).await, "hi a b\nhi c d");
+        // functions shadow builtins
+        assert_eq!(
+            stdout("function true { echo shadowed; }; true").await,
+            "shadowed"
+        );
+    }
+
+    #[tokio::test]
+    async fn positional_parameters_are_restored() {
+        let script =
+            "function inner { echo $1; }; function outer { inner x; echo $1 $2; }; outer a b";
+        assert_eq!(stdout(script).await, "x\na b");
+        // and unset again outside of functions
+        assert_eq!(run("function f { true; }; f a; echo $1").await.status, 1);
+    }
+
+    #[tokio::test]
+    async fn command_budget() {
+        let limits = Limits {
+            commands: 50,
+            ..Default::default()
+        };
+        for script in [
+            "while true; do true; done; echo after",
+            "for x in {1..100}; do echo $x; done; echo after",
+            "function f { true; }; while true; do f; done; echo after",
+        ] {
+            let (output, limit) = run_limited(script, limits).await;
+            assert_eq!(limit, Some(Limit::Commands(50)), "{script}");
+            let stdout = output.stdout.as_string().unwrap().into_string().unwrap();
+            assert!(!stdout.contains("after"), "{script} kept running");
+        }
+    }
+
+    #[tokio::test]
+    async fn run_depth() {
+        let limits = Limits {
+            run_depth: 8,
+            ..Default::default()
+        };
+        let (output, limit) = run_limited("function f { f; }; f", limits).await;
+        assert_eq!(limit, Some(Limit::RunDepth(8)));
+        // the caret is under the call that went too deep
+        let stderr = output.stderr.into_string().unwrap();
+        assert!(stderr.contains("1 | !function f { f; }; f\n  |               ^\n"));
+
+        let script = "echo $(echo $(echo $(echo x)))";
+        let limits = Limits {
+            run_depth: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            run_limited(script, limits).await.1,
+            Some(Limit::RunDepth(2))
+        );
+        let limits = Limits {
+            run_depth: 3,
+            ..Default::default()
+        };
+        assert_eq!(run_limited(script, limits).await.1, None);
+    }
+
+    #[tokio::test]
+    async fn expanded_args() {
+        let limits = Limits {
+            expanded_args: 10,
+            ..Default::default()
+        };
+        assert_eq!(
+            run_limited("echo {1..10}", limits).await.1,
+            Some(Limit::ExpandedArgs(10))
+        );
+        assert_eq!(run_limited("echo {1..9}", limits).await.1, None);
+    }
+
+    #[tokio::test]
+    async fn functions_are_checked() {
+        let output = run("function denied { echo ran; }; denied").await;
+        assert_eq!(output.status, 1);
+        assert_eq!(output.stdout, EnvironValue::None);
+        assert_eq!(run("function f { true; }; unfunction f").await.status, 1);
+        assert_eq!(
+            stdout("function allowed { echo ran; }; allowed").await,
+            "ran"
+        );
+    }
+
+    #[tokio::test]
+    async fn aliases() {
+        assert_eq!(stdout("alias ll='echo long'; ll -a").await, "long -a");
+        // an alias doesn't expand inside itself
+        assert_eq!(stdout("alias echo='echo x'; echo y").await, "x y");
+    }
+}
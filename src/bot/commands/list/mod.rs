@@ -3,8 +3,18 @@ use std::{collections::HashMap, sync::Arc};
 use lazy_static::lazy_static;
 
 lazy_static! {
-    pub static ref COMMAND_LIST: [Arc<dyn super::Command>; 1] = [Arc::new(cmd_list::Command),];
-    pub static ref COMMAND_MAP: HashMap<&'static str, Arc<dyn super::Command>> = {
+    pub static ref COMMAND_LIST: [Arc<dyn super::DynCommand>; 9] = [
+        Arc::new(cmd_help::Command),
+        Arc::new(cmd_list::Command),
+        Arc::new(cmd_echo::Command),
+        Arc::new(cmd_cat::Command),
+        Arc::new(cmd_grep::Command),
+        Arc::new(cmd_upper::Command),
+        Arc::new(cmd_react::Command),
+        Arc::new(cmd_memusage::Command),
+        Arc::new(cmd_perm::Command),
+    ];
+    pub static ref COMMAND_MAP: HashMap<&'static str, Arc<dyn super::DynCommand>> = {
         let mut m = HashMap::new();
         for cmd in COMMAND_LIST.iter() {
             m.insert(cmd.name(), Arc::clone(cmd));
@@ -13,9 +23,76 @@ lazy_static! {
     };
 }
 
+mod cmd_help {
+    use std::fmt::Display;
+
+    use clap::Parser;
+
+    use super::super::{help, parser::EnvironValue, CommandContext, CommandError, CommandResult};
+
+    /// Display list of commands, or one's help
+    #[derive(Parser, Debug)]
+    #[command(version, about, long_about = None)]
+    pub struct Args {
+        /// Show this one's usage and options
+        command: Option<String>,
+    }
+
+    #[derive(Debug)]
+    pub enum Error {
+        NoSuchCommand(String),
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::NoSuchCommand(name) => write!(f, "no command named `{name}`"),
+            }
+        }
+    }
+
+    impl CommandError for Error {
+        fn code(&self) -> &'static str {
+            match self {
+                Self::NoSuchCommand(_) => "E201",
+            }
+        }
+
+        fn hint(&self) -> Option<String> {
+            Some(String::from("`help` lists every command"))
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Command;
+
+    impl super::super::Command for Command {
+        type Args = Args;
+
+        fn name(&self) -> &'static str {
+            "help"
+        }
+        async fn run(
+            &self,
+            args: Self::Args,
+            _input: EnvironValue,
+            _ctx: CommandContext<'_>,
+        ) -> CommandResult {
+            let help = match args.command {
+                Some(name) => help::command(&name).ok_or(Error::NoSuchCommand(name))?,
+                None => help::overview(),
+            };
+
+            Ok(EnvironValue::String(help.into()).into())
+        }
+    }
+}
+
 mod cmd_list {
     use clap::Parser;
 
+    use super::super::{parser::EnvironValue, CommandContext, CommandResult};
+
     /// List commands
     #[derive(Parser, Debug)]
     #[command(version, about, long_about = None)]
@@ -30,12 +107,568 @@ mod cmd_list {
         fn name(&self) -> &'static str {
             "list"
         }
-        fn run(
+        async fn run(
+            &self,
+            _args: Self::Args,
+            _input: EnvironValue,
+            _ctx: CommandContext<'_>,
+        ) -> CommandResult {
+            Ok(EnvironValue::Map(
+                super::COMMAND_LIST
+                    .iter()
+                    .map(|cmd| {
+                        let about = cmd.clap().get_about().map(ToString::to_string);
+                        (
+                            cmd.name().to_owned(),
+                            EnvironValue::String(about.unwrap_or_default().into()),
+                        )
+                    })
+                    .collect(),
+            )
+            .into())
+        }
+    }
+}
+
+mod cmd_echo {
+    use std::ffi::OsString;
+
+    use clap::Parser;
+
+    use super::super::{parser::EnvironValue, CommandContext, CommandResult};
+
+    /// Echo 👍
+    #[derive(Parser, Debug)]
+    #[command(version, about, long_about = None)]
+    pub struct Args {
+        /// Printed space separated
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        words: Vec<OsString>,
+    }
+
+    #[derive(Default)]
+    pub struct Command;
+
+    impl super::super::Command for Command {
+        type Args = Args;
+
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+        async fn run(
+            &self,
+            args: Self::Args,
+            _input: EnvironValue,
+            _ctx: CommandContext<'_>,
+        ) -> CommandResult {
+            Ok(EnvironValue::String(args.words.join(&OsString::from(" "))).into())
+        }
+    }
+}
+
+mod cmd_cat {
+    use clap::Parser;
+
+    use super::super::{parser::EnvironValue, CommandContext, CommandResult};
+
+    /// Output the piped input as is
+    #[derive(Parser, Debug)]
+    #[command(version, about, long_about = None)]
+    pub struct Args;
+
+    #[derive(Default)]
+    pub struct Command;
+
+    impl super::super::Command for Command {
+        type Args = Args;
+
+        fn name(&self) -> &'static str {
+            "cat"
+        }
+        async fn run(
             &self,
             _args: Self::Args,
-            _ctx: serenity::prelude::Context,
-            _msg: serenity::model::prelude::Message,
-        ) {
+            input: EnvironValue,
+            _ctx: CommandContext<'_>,
+        ) -> CommandResult {
+            Ok(input.into())
+        }
+    }
+}
+
+mod cmd_grep {
+    use std::{ffi::OsString, fmt::Display, os::unix::ffi::OsStringExt};
+
+    use clap::Parser;
+
+    use super::super::{parser::EnvironValue, CommandContext, CommandError, CommandResult};
+
+    /// Filter lines of the input containing a pattern
+    #[derive(Parser, Debug)]
+    #[command(version, about, long_about = None)]
+    pub struct Args {
+        /// Matched literally, an empty one matches every line
+        pattern: OsString,
+    }
+
+    #[derive(Debug)]
+    pub enum Error {
+        BlobInput,
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::BlobInput => write!(f, "can't search a blob"),
+            }
+        }
+    }
+
+    impl CommandError for Error {
+        fn code(&self) -> &'static str {
+            match self {
+                Self::BlobInput => "E211",
+            }
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Command;
+
+    impl super::super::Command for Command {
+        type Args = Args;
+
+        fn name(&self) -> &'static str {
+            "grep"
+        }
+        async fn run(
+            &self,
+            args: Self::Args,
+            input: EnvironValue,
+            _ctx: CommandContext<'_>,
+        ) -> CommandResult {
+            let pattern = args.pattern.as_encoded_bytes();
+            let input = input.as_string().ok_or(Error::BlobInput)?;
+
+            let matches = input
+                .as_encoded_bytes()
+                .split(|b| *b == b'\n')
+                .filter(|line| {
+                    pattern.is_empty() || line.windows(pattern.len()).any(|w| w == pattern)
+                })
+                .collect::<Vec<_>>()
+                .join(&b'\n');
+
+            Ok(EnvironValue::String(OsString::from_vec(matches)).into())
+        }
+    }
+}
+
+mod cmd_upper {
+    use std::{ffi::OsString, fmt::Display};
+
+    use clap::Parser;
+
+    use super::super::{parser::EnvironValue, CommandContext, CommandError, CommandResult};
+
+    /// Uppercase the input
+    #[derive(Parser, Debug)]
+    #[command(version, about, long_about = None)]
+    pub struct Args;
+
+    #[derive(Debug)]
+    pub enum Error {
+        BlobInput,
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::BlobInput => write!(f, "can't uppercase a blob"),
+            }
+        }
+    }
+
+    impl CommandError for Error {
+        fn code(&self) -> &'static str {
+            match self {
+                Self::BlobInput => "E221",
+            }
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Command;
+
+    impl super::super::Command for Command {
+        type Args = Args;
+
+        fn name(&self) -> &'static str {
+            "upper"
+        }
+        async fn run(
+            &self,
+            _args: Self::Args,
+            input: EnvironValue,
+            _ctx: CommandContext<'_>,
+        ) -> CommandResult {
+            let input = input.as_string().ok_or(Error::BlobInput)?;
+
+            Ok(EnvironValue::String(match input.into_string() {
+                Ok(input) => OsString::from(input.to_uppercase()),
+                Err(input) => input.to_ascii_uppercase(),
+            })
+            .into())
+        }
+    }
+}
+
+mod cmd_react {
+    use std::fmt::Display;
+
+    use clap::Parser;
+    use serenity::model::channel::ReactionType;
+
+    use super::super::{parser::EnvironValue, CommandContext, CommandError, CommandResult};
+
+    /// React to the message with the given emojis
+    #[derive(Parser, Debug)]
+    #[command(version, about, long_about = None)]
+    pub struct Args {
+        emojis: Vec<String>,
+    }
+
+    #[derive(Debug)]
+    pub enum Error {
+        Discord {
+            emoji: String,
+            err: Box<serenity::Error>,
+        },
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::Discord { emoji, err } => write!(f, "couldn't react with `{emoji}`: {err}"),
+            }
+        }
+    }
+
+    impl CommandError for Error {
+        fn code(&self) -> &'static str {
+            match self {
+                Self::Discord { .. } => "E231",
+            }
+        }
+
+        fn hint(&self) -> Option<String> {
+            Some(String::from("only unicode emojis can be used"))
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Command;
+
+    impl super::super::Command for Command {
+        type Args = Args;
+
+        fn name(&self) -> &'static str {
+            "react"
+        }
+        async fn run(
+            &self,
+            args: Self::Args,
+            _input: EnvironValue,
+            ctx: CommandContext<'_>,
+        ) -> CommandResult {
+            for emoji in args.emojis {
+                ctx.msg
+                    .react(&ctx.ctx.http, ReactionType::Unicode(emoji.clone()))
+                    .await
+                    .map_err(|err| Error::Discord {
+                        emoji,
+                        err: Box::new(err),
+                    })?;
+            }
+
+            Ok(EnvironValue::None.into())
+        }
+    }
+}
+
+mod cmd_memusage {
+    use std::{ffi::OsString, fmt::Display};
+
+    use clap::Parser;
+    use procfs::WithCurrentSystemInfo;
+
+    use crate::util::humanize::units::sizes;
+
+    use super::super::{
+        parser::EnvironValue, permissions::Requirement, CommandContext, CommandError, CommandResult,
+    };
+
+    /// Print memory usage
+    #[derive(Parser, Debug)]
+    #[command(version, about, long_about = None)]
+    pub struct Args;
+
+    #[derive(Debug)]
+    pub enum Error {
+        Proc(procfs::ProcError),
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::Proc(err) => write!(f, "couldn't read the process' stats: {err}"),
+            }
+        }
+    }
+
+    impl CommandError for Error {
+        fn code(&self) -> &'static str {
+            match self {
+                Self::Proc(_) => "E241",
+            }
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Command;
+
+    impl super::super::Command for Command {
+        type Args = Args;
+
+        fn name(&self) -> &'static str {
+            "memusage"
+        }
+        fn requirement(&self) -> Requirement {
+            Requirement::OWNER_ONLY
+        }
+        async fn run(
+            &self,
+            _args: Self::Args,
+            _input: EnvironValue,
+            _ctx: CommandContext<'_>,
+        ) -> CommandResult {
+            let stat = procfs::process::Process::myself()
+                .and_then(|me| me.stat())
+                .map_err(Error::Proc)?;
+
+            Ok(EnvironValue::String(OsString::from(format!(
+                "pid({}): rss({}) vsize({})",
+                stat.pid,
+                sizes::bytes_to_binary(stat.rss_bytes().get() as f64, 2),
+                sizes::bytes_to_binary(stat.vsize as f64, 2),
+            )))
+            .into())
+        }
+    }
+}
+
+mod cmd_perm {
+    use std::fmt::Display;
+
+    use clap::{Parser, Subcommand};
+    use serenity::model::{
+        id::{ChannelId, RoleId},
+        Permissions,
+    };
+
+    use super::super::{
+        help,
+        parser::EnvironValue,
+        permissions::{Requirement, Rule, Rules, Target},
+        CommandContext, CommandError, CommandResult,
+    };
+
+    /// Allow or deny commands to roles and channels
+    #[derive(Parser, Debug)]
+    #[command(version, about, long_about = None)]
+    pub struct Args {
+        #[command(subcommand)]
+        action: Action,
+    }
+
+    #[derive(Subcommand, Debug)]
+    enum Action {
+        /// Show the server's rules, or a command's
+        List { command: Option<String> },
+        /// Let a role or channel run a command, even without its permissions
+        Allow {
+            command: String,
+            #[command(flatten)]
+            target: TargetArgs,
+        },
+        /// Stop a role or channel from running a command
+        Deny {
+            command: String,
+            #[command(flatten)]
+            target: TargetArgs,
+        },
+        /// Remove a command's rules, or only the one for a role or channel
+        Reset {
+            command: String,
+            /// A role's ID or mention
+            #[arg(long, value_parser = role, conflicts_with = "channel")]
+            role: Option<RoleId>,
+            /// A channel's ID or mention
+            #[arg(long, value_parser = channel)]
+            channel: Option<ChannelId>,
+        },
+    }
+
+    #[derive(clap::Args, Debug)]
+    #[group(required = true, multiple = false)]
+    struct TargetArgs {
+        /// A role's ID or mention
+        #[arg(long, value_parser = role)]
+        role: Option<RoleId>,
+        /// A channel's ID or mention
+        #[arg(long, value_parser = channel)]
+        channel: Option<ChannelId>,
+    }
+
+    /// An ID or the mention starting with `prefix`
+    fn id(arg: &str, prefix: &str) -> Result<u64, String> {
+        let id = arg
+            .strip_prefix(prefix)
+            .and_then(|arg| arg.strip_suffix('>'))
+            .unwrap_or(arg);
+        id.parse()
+            .ok()
+            .filter(|id| *id != 0)
+            .ok_or_else(|| format!("`{arg}` isn't an ID or a mention"))
+    }
+
+    fn role(arg: &str) -> Result<RoleId, String> {
+        id(arg, "<@&").map(RoleId::new)
+    }
+
+    fn channel(arg: &str) -> Result<ChannelId, String> {
+        id(arg, "<#").map(ChannelId::new)
+    }
+
+    impl TargetArgs {
+        fn target(&self) -> Target {
+            match (self.role, self.channel) {
+                (Some(role), _) => Target::Role(role),
+                (_, Some(channel)) => Target::Channel(channel),
+                (None, None) => unreachable!("clap requires one of them"),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum Error {
+        NotInGuild,
+        UnknownCommand(String),
+        Database(Box<surrealdb::Error>),
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::NotInGuild => write!(f, "rules can only be set in servers"),
+                Self::UnknownCommand(name) => write!(f, "no command named `{name}`"),
+                Self::Database(err) => write!(f, "couldn't reach the database: {err}"),
+            }
+        }
+    }
+
+    impl CommandError for Error {
+        fn code(&self) -> &'static str {
+            match self {
+                Self::NotInGuild => "E251",
+                Self::UnknownCommand(_) => "E252",
+                Self::Database(_) => "E253",
+            }
+        }
+
+        fn hint(&self) -> Option<String> {
+            match self {
+                Self::UnknownCommand(_) => Some(String::from(
+                    "only commands have rules, functions named as one follow them",
+                )),
+                _ => None,
+            }
+        }
+    }
+
+    /// Only commands and builtins can have rules, functions are checked
+    /// under their name and aliases run commands that get checked
+    fn set(rules: &mut Rules, command: String, target: Target, allow: bool) -> Result<(), Error> {
+        let builtin = help::BUILTINS.iter().any(|cmd| cmd.get_name() == command);
+        if !super::COMMAND_MAP.contains_key(command.as_str()) && !builtin {
+            do yeet Error::UnknownCommand(command);
+        }
+
+        rules.set(Rule {
+            command,
+            target,
+            allow,
+        });
+        Ok(())
+    }
+
+    #[derive(Default)]
+    pub struct Command;
+
+    impl super::super::Command for Command {
+        type Args = Args;
+
+        fn name(&self) -> &'static str {
+            "perm"
+        }
+        fn requirement(&self) -> Requirement {
+            Requirement::permissions(Permissions::MANAGE_GUILD)
+        }
+        async fn run(
+            &self,
+            args: Self::Args,
+            _input: EnvironValue,
+            ctx: CommandContext<'_>,
+        ) -> CommandResult {
+            let guild_id = ctx.msg.guild_id.ok_or(Error::NotInGuild)?;
+            let database = |err| Error::Database(Box::new(err));
+            let mut rules = Rules::load(ctx.db, guild_id).await.map_err(database)?;
+
+            let changed = match args.action {
+                Action::List { command } => {
+                    let lines: Vec<_> = rules
+                        .rules
+                        .iter()
+                        .filter(|rule| command.as_ref().is_none_or(|cmd| rule.command == *cmd))
+                        .map(|rule| {
+                            let action = if rule.allow { "allow" } else { "deny" };
+                            format!("{action} {} for {}", rule.command, rule.target)
+                        })
+                        .collect();
+                    return Ok(EnvironValue::String(lines.join("\n").into()).into());
+                }
+                Action::Allow { command, target } => {
+                    set(&mut rules, command, target.target(), true)?;
+                    true
+                }
+                Action::Deny { command, target } => {
+                    set(&mut rules, command, target.target(), false)?;
+                    true
+                }
+                Action::Reset {
+                    command,
+                    role,
+                    channel,
+                } => {
+                    let target = role.map(Target::Role).or(channel.map(Target::Channel));
+                    rules.remove(&command, target) > 0
+                }
+            };
+
+            if changed {
+                rules.store(ctx.db, guild_id).await.map_err(database)?;
+            }
+            Ok(EnvironValue::None.into())
         }
     }
 }
@@ -1,34 +1,258 @@
+pub mod arithmetic;
+pub mod brace;
+pub mod context;
+pub mod definitions;
+pub mod diagnostic;
+pub mod help;
+pub mod interpreter;
 pub mod list;
 pub mod parser;
-
-use crate::util::humanize::units::sizes;
+pub mod permissions;
+pub mod printer;
+pub mod ratelimit;
 
 use std::{
+    borrow::Cow,
+    cell::OnceCell,
     collections::HashMap,
-    ffi::{OsStr, OsString},
+    ffi::OsString,
+    fmt::{Debug, Display},
+    future::Future,
+    mem::take,
     os::unix::ffi::OsStringExt,
 };
 
-use procfs::WithCurrentSystemInfo;
+use clap::{CommandFactory, FromArgMatches};
+use futures::future::BoxFuture;
 use serenity::{model::channel::Message, prelude::*};
 
-pub type DefaultEnviron<'a> = HashMap<String, parser::EnvironValue>;
-impl<'a> parser::Environ<'a> for DefaultEnviron<'a> {
-    fn get(&self, key: &str) -> Option<&parser::EnvironValue> {
-        self.get(key)
+#[derive(Debug, Default)]
+pub struct DefaultEnviron {
+    values: HashMap<String, parser::EnvironValue>,
+    attributes: HashMap<String, parser::Attributes>,
+}
+
+impl DefaultEnviron {
+    /// Sets `key` regardless of its current attributes, for seeding
+    pub fn insert(
+        &mut self,
+        key: String,
+        value: parser::EnvironValue,
+        attributes: parser::Attributes,
+    ) {
+        self.attributes.insert(key.clone(), attributes);
+        self.values.insert(key, value);
+    }
+}
+
+impl<'a> parser::Environ<'a> for DefaultEnviron {
+    fn get(&self, key: &str) -> Option<Cow<'_, parser::EnvironValue>> {
+        self.values.get(key).map(Cow::Borrowed)
+    }
+
+    fn set(
+        &mut self,
+        key: String,
+        value: parser::EnvironValue,
+    ) -> Result<Option<parser::EnvironValue>, parser::EnvironError> {
+        let value = parser::Environ::attributes(self, &key).check(&key, value)?;
+        Ok(self.values.insert(key, value))
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<parser::EnvironValue>, parser::EnvironError> {
+        if parser::Environ::attributes(self, key).readonly {
+            do yeet parser::EnvironError::Readonly(key.to_owned());
+        }
+        Ok(self.values.remove(key))
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (&str, &parser::EnvironValue)> {
+        self.values.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    fn attributes(&self, key: &str) -> parser::Attributes {
+        self.attributes.get(key).copied().unwrap_or_default()
+    }
+
+    fn declare(
+        &mut self,
+        key: String,
+        attributes: parser::Attributes,
+    ) -> Result<(), parser::EnvironError> {
+        if !parser::Attributes::can_declare(&key) {
+            do yeet parser::EnvironError::InvalidName(key);
+        }
+        let attributes = parser::Environ::attributes(self, &key).merge(&key, attributes)?;
+
+        if let (Some(kind), Some(value)) = (attributes.kind, self.values.get_mut(&key)) {
+            *value = kind
+                .coerce(take(value))
+                .ok_or(parser::EnvironError::WrongType {
+                    name: key.clone(),
+                    kind,
+                })?;
+        }
+        self.attributes.insert(key, attributes);
+        Ok(())
+    }
+}
+
+type Resolver<'a> = Box<dyn Fn() -> parser::EnvironValue + Send + 'a>;
+
+/// Values that are expensive to get are only resolved when first read,
+/// unless something was set there before. Volatile ones are resolved again
+/// on every read.
+#[derive(Default)]
+pub struct LazyEnviron<'a> {
+    values: DefaultEnviron,
+    lazy: HashMap<String, (OnceCell<parser::EnvironValue>, Resolver<'a>)>,
+    volatile: HashMap<String, Resolver<'a>>,
+}
+
+impl<'a> LazyEnviron<'a> {
+    /// See [`DefaultEnviron::insert`]
+    pub fn insert(
+        &mut self,
+        key: String,
+        value: parser::EnvironValue,
+        attributes: parser::Attributes,
+    ) -> &mut Self {
+        self.lazy.remove(&key);
+        self.volatile.remove(&key);
+        self.values.insert(key, value, attributes);
+        self
+    }
+
+    pub fn lazy(
+        &mut self,
+        key: String,
+        attributes: parser::Attributes,
+        resolver: impl Fn() -> parser::EnvironValue + Send + 'a,
+    ) -> &mut Self {
+        self.values.attributes.insert(key.clone(), attributes);
+        self.lazy.insert(key, (OnceCell::new(), Box::new(resolver)));
+        self
+    }
+
+    pub fn volatile(
+        &mut self,
+        key: String,
+        attributes: parser::Attributes,
+        resolver: impl Fn() -> parser::EnvironValue + Send + 'a,
+    ) -> &mut Self {
+        self.values.attributes.insert(key.clone(), attributes);
+        self.volatile.insert(key, Box::new(resolver));
+        self
+    }
+}
+
+impl<'a> parser::Environ<'a> for LazyEnviron<'a> {
+    fn get(&self, key: &str) -> Option<Cow<'_, parser::EnvironValue>> {
+        parser::Environ::get(&self.values, key)
+            .or_else(|| {
+                let (value, resolver) = self.lazy.get(key)?;
+                Some(Cow::Borrowed(value.get_or_init(resolver)))
+            })
+            .or_else(|| Some(Cow::Owned(self.volatile.get(key)?())))
+    }
+
+    fn set(
+        &mut self,
+        key: String,
+        value: parser::EnvironValue,
+    ) -> Result<Option<parser::EnvironValue>, parser::EnvironError> {
+        let old = parser::Environ::set(&mut self.values, key.clone(), value)?;
+        let lazy = self
+            .lazy
+            .remove(&key)
+            .and_then(|(value, _)| value.into_inner());
+        let volatile = self.volatile.remove(&key).map(|resolver| resolver());
+        Ok(old.or(lazy).or(volatile))
     }
 
-    fn set(&mut self, key: String, value: parser::EnvironValue) -> Option<parser::EnvironValue> {
-        self.insert(key, value)
+    fn remove(&mut self, key: &str) -> Result<Option<parser::EnvironValue>, parser::EnvironError> {
+        let old = parser::Environ::remove(&mut self.values, key)?;
+        let lazy = self
+            .lazy
+            .remove(key)
+            .and_then(|(value, _)| value.into_inner());
+        let volatile = self.volatile.remove(key).map(|resolver| resolver());
+        Ok(old.or(lazy).or(volatile))
     }
 
+    /// Lazy values that were never read and volatile ones aren't listed
     fn entries(&self) -> impl Iterator<Item = (&str, &parser::EnvironValue)> {
-        self.iter().map(|(k, v)| (k.as_str(), v))
+        let resolved = self
+            .lazy
+            .iter()
+            .filter_map(|(k, (v, _))| Some((k.as_str(), v.get()?)));
+        parser::Environ::entries(&self.values).chain(resolved)
+    }
+
+    fn attributes(&self, key: &str) -> parser::Attributes {
+        parser::Environ::attributes(&self.values, key)
+    }
+
+    /// Unresolved and volatile values get resolved first, so a new kind applies to them
+    fn declare(
+        &mut self,
+        key: String,
+        attributes: parser::Attributes,
+    ) -> Result<(), parser::EnvironError> {
+        if let Some((value, resolver)) = self.lazy.remove(&key) {
+            let value = value.into_inner().unwrap_or_else(resolver);
+            self.values.values.insert(key.clone(), value);
+        }
+        if let Some(resolver) = self.volatile.remove(&key) {
+            self.values.values.insert(key.clone(), resolver());
+        }
+        parser::Environ::declare(&mut self.values, key, attributes)
+    }
+}
+
+/// The database the bot stores its data in
+pub type Database = surrealdb::Surreal<surrealdb::engine::remote::ws::Client>;
+
+/// What a message's commands can reach outside the interpreter
+#[derive(Clone, Copy)]
+pub struct CommandContext<'a> {
+    pub ctx: &'a Context,
+    pub msg: &'a Message,
+    pub db: &'a Database,
+}
+
+/// What a [`Command`] outputs, or why it failed
+pub type CommandResult = Result<parser::Output, Box<dyn CommandError>>;
+
+/// Errors of commands and executers, rendered as `error[CODE]: message`
+/// with the hint under it. Codes are listed in the README and never reused.
+pub trait CommandError: Display + Debug + Send + Sync {
+    fn code(&self) -> &'static str;
+    fn hint(&self) -> Option<String> {
+        None
     }
 }
 
+impl<T: CommandError + ?Sized> diagnostic::Diagnostic for T {
+    fn code(&self) -> Option<&'static str> {
+        Some(CommandError::code(self))
+    }
+
+    fn hint(&self) -> Option<String> {
+        CommandError::hint(self)
+    }
+}
+
+impl<T: CommandError + 'static> From<T> for Box<dyn CommandError> {
+    fn from(err: T) -> Self {
+        Box::new(err)
+    }
+}
+
+/// Implement it and add it to [`list::COMMAND_LIST`] to make it callable,
+/// [`RegistryExecuter`] parses its `Args` from what it's called with
 pub trait Command: Send + Sync {
-    type Args: clap::Parser
+    type Args: clap::Parser + Send
     where
         Self: Sized;
 
@@ -36,17 +260,99 @@ pub trait Command: Send + Sync {
     fn description(&self) -> &'static str {
         ""
     }
+    /// Checked before it runs, see [`permissions::Access::check`]
+    fn requirement(&self) -> permissions::Requirement {
+        permissions::Requirement::default()
+    }
     fn init() -> Self
     where
         Self: Default,
     {
         Self::default()
     }
-    fn run(&self, args: Self::Args, ctx: Context, msg: Message)
+    /// `input` is the stdout of the previous command in the pipeline
+    fn run(
+        &self,
+        args: Self::Args,
+        input: parser::EnvironValue,
+        ctx: CommandContext<'_>,
+    ) -> impl Future<Output = CommandResult> + Send
     where
         Self: Sized;
 }
 
+/// A [`Command`] with its `Args` type erased, as kept in
+/// [`list::COMMAND_MAP`]
+pub trait DynCommand: Command {
+    /// The clap definition of its args, named as the command
+    fn clap(&self) -> clap::Command;
+
+    /// Parses `args`, the first being the command's name, then runs it.
+    /// Usage errors and `--help` are outputs, like a program printing them.
+    fn execute<'s>(
+        &'s self,
+        args: Vec<OsString>,
+        input: parser::EnvironValue,
+        ctx: Option<CommandContext<'s>>,
+    ) -> BoxFuture<'s, Result<parser::Output, RegistryExecuterError>>;
+}
+
+impl<T: Command> DynCommand for T {
+    fn clap(&self) -> clap::Command {
+        T::Args::command().name(self.name()).styles(help::styles())
+    }
+
+    fn execute<'s>(
+        &'s self,
+        args: Vec<OsString>,
+        input: parser::EnvironValue,
+        ctx: Option<CommandContext<'s>>,
+    ) -> BoxFuture<'s, Result<parser::Output, RegistryExecuterError>> {
+        Box::pin(async move {
+            let parsed = self
+                .clap()
+                .try_get_matches_from(args)
+                .and_then(|matches| T::Args::from_arg_matches(&matches));
+            let args = match parsed {
+                Ok(args) => args,
+                Err(err) if err.use_stderr() => {
+                    return Ok(parser::Output::failure(
+                        err.exit_code() as u8,
+                        err.render().ansi().to_string(),
+                    ));
+                }
+                Err(err) => {
+                    let help = err.render().ansi().to_string();
+                    return Ok(parser::EnvironValue::String(help.into()).into());
+                }
+            };
+
+            let ctx = ctx.ok_or(RegistryExecuterError::NoContext)?;
+            self.run(args, input, ctx)
+                .await
+                .map_err(RegistryExecuterError::Command)
+        })
+    }
+}
+
+/// Declares `key` exported and sets it, as builtins defining variables do
+fn define<'a>(
+    env: &mut impl parser::Environ<'a>,
+    key: String,
+    value: parser::EnvironValue,
+) -> Result<(), HardcodedExecuterError> {
+    let exported = parser::Attributes {
+        exported: true,
+        ..Default::default()
+    };
+    env.declare(key.clone(), exported)
+        .map_err(HardcodedExecuterError::Environ)?;
+    env.set(key, value)
+        .map_err(HardcodedExecuterError::Environ)?;
+    Ok(())
+}
+
+/// Runs the shell builtins, the ones working on the environment
 #[derive(Default)]
 pub struct HardcodedExecuter;
 
@@ -54,21 +360,202 @@ pub struct HardcodedExecuter;
 pub enum HardcodedExecuterError {
     NoCommandName,
     ImproperEncoding,
-    UnknownCommand,
-    // TODO: this will be a enum for each cmd
-    // ig they'll have a common trait for display
-    // aaand, be a dyn prob
-    CommandError(&'static str),
+    UnknownCommand(String),
+    InvalidArgCount {
+        expected: usize,
+        got: usize,
+    },
+    /// `let` and `map` entries are `key=value`
+    NoEquals,
+    NoName,
+    UnknownFlag(char),
     NoStringCommandName,
     UnserializableValue,
+    Environ(parser::EnvironError),
+}
+
+impl Display for HardcodedExecuterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoCommandName => write!(f, "no command given"),
+            Self::ImproperEncoding => write!(f, "argument isn't valid UTF-8"),
+            Self::UnknownCommand(name) => write!(f, "unknown command `{name}`"),
+            Self::InvalidArgCount { expected, got } => {
+                write!(f, "expected {expected} args, got {got}")
+            }
+            Self::NoEquals => write!(f, "no `=` found"),
+            Self::NoName => write!(f, "no name given"),
+            Self::UnknownFlag(flag) => write!(f, "unknown flag `-{flag}`"),
+            Self::NoStringCommandName => write!(f, "command name isn't text"),
+            Self::UnserializableValue => write!(f, "value can't be turned into text"),
+            Self::Environ(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl CommandError for HardcodedExecuterError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::NoCommandName => "E101",
+            Self::ImproperEncoding => "E102",
+            Self::UnknownCommand(_) => "E103",
+            Self::InvalidArgCount { .. } => "E104",
+            Self::NoEquals => "E105",
+            Self::NoName => "E106",
+            Self::UnknownFlag(_) => "E107",
+            Self::NoStringCommandName => "E108",
+            Self::UnserializableValue => "E109",
+            Self::Environ(err) => err.code(),
+        }
+    }
+
+    fn hint(&self) -> Option<String> {
+        match self {
+            Self::UnknownCommand(_) => Some(String::from("`help` lists every command")),
+            Self::NoEquals => Some(String::from("write it as `name=value`")),
+            Self::UnknownFlag(_) => Some(String::from("expected one of -r -x -s -i -a -A")),
+            Self::Environ(err) => err.hint(),
+            _ => None,
+        }
+    }
+}
+
+/// Runs the commands in [`list::COMMAND_MAP`], the shell builtins in
+/// [`HardcodedExecuter`] otherwise. `context` and `access` are `None`
+/// outside of Discord, commands fail there and nothing is checked.
+#[derive(Default)]
+pub struct RegistryExecuter<'a> {
+    pub context: Option<CommandContext<'a>>,
+    pub access: Option<permissions::Access>,
+    /// `None` for the bot's owners
+    pub rate_limiter: Option<&'a ratelimit::RateLimiter>,
+    builtins: HardcodedExecuter,
+}
+
+impl<'a> RegistryExecuter<'a> {
+    pub fn new(
+        context: CommandContext<'a>,
+        access: permissions::Access,
+        rate_limiter: Option<&'a ratelimit::RateLimiter>,
+    ) -> Self {
+        Self {
+            context: Some(context),
+            access: Some(access),
+            rate_limiter,
+            builtins: HardcodedExecuter,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RegistryExecuterError {
+    NoCommandName,
+    NoStringCommandName,
+    ImproperEncoding,
+    UnserializableValue,
+    /// Ran outside of Discord
+    NoContext,
+    /// The command's or builtin's own error
+    Command(Box<dyn CommandError>),
+}
+
+impl Display for RegistryExecuterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoCommandName => write!(f, "no command given"),
+            Self::NoStringCommandName => write!(f, "command name isn't text"),
+            Self::ImproperEncoding => write!(f, "command name isn't valid UTF-8"),
+            Self::UnserializableValue => write!(f, "argument can't be turned into text"),
+            Self::NoContext => write!(f, "command only runs on Discord"),
+            Self::Command(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl CommandError for RegistryExecuterError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::NoCommandName => "E001",
+            Self::NoStringCommandName => "E002",
+            Self::ImproperEncoding => "E003",
+            Self::UnserializableValue => "E004",
+            Self::NoContext => "E005",
+            Self::Command(err) => err.code(),
+        }
+    }
+
+    fn hint(&self) -> Option<String> {
+        match self {
+            Self::UnserializableValue => Some(String::from("blobs can only be piped")),
+            Self::Command(err) => err.hint(),
+            _ => None,
+        }
+    }
+}
+
+impl parser::Executer<RegistryExecuterError> for RegistryExecuter<'_> {
+    async fn execute<'a>(
+        &mut self,
+        args: Vec<parser::EnvironValue>,
+        input: parser::EnvironValue,
+        env: &mut (impl parser::Environ<'a> + Send),
+    ) -> Result<parser::Output, RegistryExecuterError> {
+        let parser::EnvironValue::String(name) =
+            args.first().ok_or(RegistryExecuterError::NoCommandName)?
+        else {
+            do yeet RegistryExecuterError::NoStringCommandName;
+        };
+        let name = name
+            .to_str()
+            .ok_or(RegistryExecuterError::ImproperEncoding)?;
+
+        parser::Executer::check(self, name)?;
+        if let (Some(limiter), Some(context)) = (self.rate_limiter, &self.context) {
+            limiter
+                .command(context.msg.author.id, context.msg.channel_id, name)
+                .map_err(|err| RegistryExecuterError::Command(err.into()))?;
+        }
+
+        let Some(command) = list::COMMAND_MAP.get(name) else {
+            return self
+                .builtins
+                .execute(args, input, env)
+                .await
+                .map_err(|err| RegistryExecuterError::Command(err.into()));
+        };
+
+        let args = args
+            .into_iter()
+            .map(|arg| {
+                arg.as_string()
+                    .ok_or(RegistryExecuterError::UnserializableValue)
+            })
+            .try_collect()?;
+        command.execute(args, input, self.context).await
+    }
+
+    fn check(&self, name: &str) -> Result<(), RegistryExecuterError> {
+        let Some(access) = &self.access else {
+            return Ok(());
+        };
+        let requirement = list::COMMAND_MAP
+            .get(name)
+            .map(|cmd| cmd.requirement())
+            .or_else(|| definitions::Definitions::requirement(name))
+            .unwrap_or_default();
+        access
+            .check(name, requirement)
+            .map_err(|err| RegistryExecuterError::Command(err.into()))
+    }
 }
 
 impl parser::Executer<HardcodedExecuterError> for HardcodedExecuter {
-    fn execute<'a>(
+    async fn execute<'a>(
         &mut self,
         mut args: Vec<parser::EnvironValue>,
-        env: &mut impl parser::Environ<'a>,
-    ) -> Result<parser::EnvironValue, HardcodedExecuterError> {
+        _input: parser::EnvironValue,
+        env: &mut (impl parser::Environ<'a> + Send),
+    ) -> Result<parser::Output, HardcodedExecuterError> {
         let parser::EnvironValue::String(cmd) =
             args.first().ok_or(HardcodedExecuterError::NoCommandName)?
         else {
@@ -77,51 +564,80 @@ impl parser::Executer<HardcodedExecuterError> for HardcodedExecuter {
         let cmd = cmd
             .to_str()
             .ok_or(HardcodedExecuterError::ImproperEncoding)?;
+        if let Some(help) = help::builtin_help(&args) {
+            return Ok(help);
+        }
 
-        match cmd {
-            "help" => Ok(concat!(
-                "Hardcoded Executer:\n\n",
-                "Commands:\n",
-                "  'help': Display list of commands\n",
-                "  'echo': Echo 👍\n",
-                "  'env': List env variables\n",
-                "  'let': Define an env variable\n",
-                "  'printargs': Prints arguments\n",
-                "  'memusage': Print memory usage\n",
-                "  'music': Full separate music handler",
-            )
-            .into()),
-            "echo" => Ok(parser::EnvironValue::String(
-                args.into_iter()
-                    .skip(1)
-                    .map(|v| {
-                        v.as_string()
-                            .ok_or(HardcodedExecuterError::UnserializableValue)
+        let stdout = match cmd {
+            "true" => return Ok(parser::Output::default()),
+            "false" => return Ok(parser::Output::failure(1, "")),
+            "env" => Ok(parser::EnvironValue::Map(
+                env.entries()
+                    .filter_map(|(k, v)| {
+                        let attributes = env.attributes(k);
+                        let v = match attributes.secret {
+                            true => parser::EnvironValue::String(OsString::from("***")),
+                            false => v.clone(),
+                        };
+                        attributes.exported.then(|| (k.to_owned(), v))
                     })
-                    .try_collect::<Vec<_>>()? // I hate this, but idk, no try_map and idwanna do
-                    // mine
-                    .iter()
-                    .intersperse(&OsString::from(" "))
-                    .fold(OsString::new(), |mut acc, v| {
-                        acc.push(v);
-                        acc
-                    }),
+                    .collect(),
             )),
-            "env" => {
-                let result = env
-                    .entries()
-                    .map(|(k, v)| format!("{k}={v:?}"))
-                    .intersperse(String::from("\n"))
-                    .fold(OsString::new(), |mut acc, new| {
-                        acc.push(OsString::from(new));
-                        acc
-                    });
-
-                Ok(parser::EnvironValue::String(result))
+            "declare" => {
+                let mut attributes = parser::Attributes::default();
+                let mut names = vec![];
+                for arg in args.into_iter().skip(1) {
+                    let arg = arg
+                        .as_string()
+                        .and_then(|arg| arg.into_string().ok())
+                        .ok_or(HardcodedExecuterError::ImproperEncoding)?;
+
+                    match arg.strip_prefix('-') {
+                        Some(flags) if names.is_empty() => {
+                            for flag in flags.chars() {
+                                match flag {
+                                    'r' => attributes.readonly = true,
+                                    'x' => attributes.exported = true,
+                                    's' => attributes.secret = true,
+                                    'i' => attributes.kind = Some(parser::ValueKind::Integer),
+                                    'a' => attributes.kind = Some(parser::ValueKind::Array),
+                                    'A' => attributes.kind = Some(parser::ValueKind::Map),
+                                    _ => do yeet HardcodedExecuterError::UnknownFlag(flag),
+                                }
+                            }
+                        }
+                        _ => names.push(arg),
+                    }
+                }
+
+                for name in names {
+                    // the value goes in before it's readonly
+                    let (name, value) = match name.split_once('=') {
+                        Some((name, value)) => (name.to_owned(), Some(value.to_owned())),
+                        None => (name, None),
+                    };
+                    let typed = parser::Attributes {
+                        readonly: false,
+                        ..attributes
+                    };
+                    env.declare(name.clone(), typed)
+                        .map_err(HardcodedExecuterError::Environ)?;
+                    if let Some(value) = value {
+                        env.set(name.clone(), parser::EnvironValue::String(value.into()))
+                            .map_err(HardcodedExecuterError::Environ)?;
+                    }
+                    env.declare(name, attributes)
+                        .map_err(HardcodedExecuterError::Environ)?;
+                }
+
+                Ok(parser::EnvironValue::None)
             }
             "let" => {
                 if args.len() != 2 {
-                    do yeet HardcodedExecuterError::CommandError("invalid arg count")
+                    do yeet HardcodedExecuterError::InvalidArgCount {
+                        expected: 1,
+                        got: args.len() - 1,
+                    }
                 }
 
                 let expr = args
@@ -132,49 +648,68 @@ impl parser::Executer<HardcodedExecuterError> for HardcodedExecuter {
                 let (k, v) = expr
                     .as_encoded_bytes()
                     .split_once(|b| *b == b'=')
-                    .ok_or(HardcodedExecuterError::CommandError("no '=' found"))?;
+                    .ok_or(HardcodedExecuterError::NoEquals)?;
 
-                let k = String::from_utf8(k.to_vec()).map_err(|_| {
-                    HardcodedExecuterError::CommandError("key is not a utf8 sequence")
-                })?;
+                let k = String::from_utf8(k.to_vec())
+                    .map_err(|_| HardcodedExecuterError::ImproperEncoding)?;
 
                 let v = OsString::from_vec(v.to_vec());
 
-                env.set(k, parser::EnvironValue::String(v)); // no v=$(stuff) btw
+                define(env, k, parser::EnvironValue::String(v))?; // no v=$(stuff) btw
 
                 Ok(parser::EnvironValue::None)
             }
-            "printargs" => {
-                Ok(parser::EnvironValue::String(OsString::from(format!("{args:?}"))))
-            }
-            "memusage" => {
-                let me = procfs::process::Process::myself().unwrap();
-                let stat = me.stat().unwrap();
-
-                Ok(parser::EnvironValue::String(OsString::from(format!(
-                    "pid({}): rss({}) vsize({})",
-                    stat.pid,
-                    sizes::bytes_to_binary(stat.rss_bytes().get() as f64, 2),
-                    sizes::bytes_to_binary(stat.vsize as f64, 2),
-                ))))
+            "array" => {
+                let mut args = args.into_iter().skip(1);
+                let k = args
+                    .next()
+                    .ok_or(HardcodedExecuterError::NoName)?
+                    .as_string()
+                    .and_then(|k| k.into_string().ok())
+                    .ok_or(HardcodedExecuterError::ImproperEncoding)?;
+
+                define(env, k, parser::EnvironValue::Array(args.collect()))?;
+
+                Ok(parser::EnvironValue::None)
             }
-            "music" => {
-                let Some(nice_args): Option<Vec<String>> = args[1..].into_iter().map(|osstr| {
-                    let parser::EnvironValue::String(osstr) = osstr else { return None; };
-                    osstr.clone().into_string().ok()
-                }).try_collect() else {
-                    return Ok(parser::EnvironValue::String(OsString::from("invalid encoding argument")))
-                };
-                let response = hardcoded_music_player::main_handler(nice_args.as_slice());
-                Ok(parser::EnvironValue::String(OsString::from(response)))
+            "map" => {
+                let mut args = args.into_iter().skip(1);
+                let k = args
+                    .next()
+                    .ok_or(HardcodedExecuterError::NoName)?
+                    .as_string()
+                    .and_then(|k| k.into_string().ok())
+                    .ok_or(HardcodedExecuterError::ImproperEncoding)?;
+
+                let entries = args
+                    .map(|entry| {
+                        let entry = entry
+                            .as_string()
+                            .ok_or(HardcodedExecuterError::UnserializableValue)?;
+                        let (key, value) = entry
+                            .as_encoded_bytes()
+                            .split_once(|b| *b == b'=')
+                            .ok_or(HardcodedExecuterError::NoEquals)?;
+                        let key = String::from_utf8(key.to_vec())
+                            .map_err(|_| HardcodedExecuterError::ImproperEncoding)?;
+
+                        Ok((
+                            key,
+                            parser::EnvironValue::String(OsString::from_vec(value.to_vec())),
+                        ))
+                    })
+                    .try_collect()?;
+
+                define(env, k, parser::EnvironValue::Map(entries))?;
+
+                Ok(parser::EnvironValue::None)
             }
-            _ => Err(HardcodedExecuterError::UnknownCommand),
-        }
-    }
-}
+            "printargs" => Ok(parser::EnvironValue::String(OsString::from(format!(
+                "{args:?}"
+            )))),
+            cmd => Err(HardcodedExecuterError::UnknownCommand(cmd.to_owned())),
+        }?;
 
-pub mod hardcoded_music_player {
-    pub fn main_handler(args: &[String]) -> String {
-        String::new()
+        Ok(stdout.into())
     }
 }
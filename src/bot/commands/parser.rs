@@ -1,8 +1,21 @@
 // Spaghetti code 😬
 // such a mess ong
 
+use crate::util::humanize::units::sizes;
+
+use super::{
+    arithmetic, brace,
+    diagnostic::{Diagnostic, Span, Spanned},
+};
+
+use serde::{Deserialize, Serialize};
+
 use std::{
+    borrow::Cow,
+    collections::BTreeMap,
     ffi::{OsStr, OsString},
+    fmt::{Debug, Display},
+    future::Future,
     iter::Peekable,
     mem::take,
     os::unix::ffi::OsStringExt,
@@ -12,6 +25,19 @@ pub struct MsgParser<'a> {
     prefix: &'a str,
     /// Line iterator
     data: Box<dyn Iterator<Item = &'a str> + 'a>,
+    /// Last line read, starting at 1
+    line: usize,
+    max_depth: Option<usize>,
+}
+
+/// Where the current line is relative to code blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Outside,
+    /// After a lone `run`, the next line opens the script
+    Awaiting,
+    /// Inside a ```` ``` ```` fence, lines don't need the prefix
+    Fence,
 }
 
 impl<'a> MsgParser<'a> {
@@ -19,65 +45,318 @@ impl<'a> MsgParser<'a> {
         Self {
             prefix,
             data: Box::new(msg.lines()),
+            line: 0,
+            max_depth: None,
         }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<ShellArgs>, ParseError> {
+    /// How deep subshells, quotes and blocks can nest, unlimited by default
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn parse(&mut self) -> Result<Vec<CommandList>, Spanned<ParseError>> {
         let mut shell_commands = vec![];
         let mut parser = None;
+        // tokens of a block (`if`, `for`...) still waiting for its end, the
+        // lines after the first one don't need the prefix
+        let mut pending: Vec<(Token, Span)> = vec![];
+        let mut block = Block::Outside;
+        // the line that opened the current code block
+        let mut block_start = Span::default();
+        let mut line_span = Span::default();
 
         for line in &mut *self.data {
-            let (mut local_parser, line_iter) = match parser {
-                Some(parser) => (parser, line.chars()),
-                None => {
-                    if !line.starts_with(self.prefix) {
+            self.line += 1;
+            line_span = Span::new(self.line, 0, line.chars().count());
+            if block != Block::Fence {
+                block_start = line_span;
+            }
+            let at_line = |err| Spanned::new(err, line_span);
+
+            // set when the line ends a script, which can't leave a command
+            // unfinished
+            let (code, script_end) = match block {
+                Block::Fence => match line.trim_end().strip_suffix("```") {
+                    Some(code) => {
+                        block = Block::Outside;
+                        (code, true)
+                    }
+                    None => (line, false),
+                },
+                Block::Awaiting => {
+                    let line = line.trim_start();
+                    if !line.starts_with('`') {
+                        do yeet at_line(ParseError::ExpectedCodeBlock);
+                    }
+                    match Self::code(line, &mut block).map_err(at_line)? {
+                        Some(code) => code,
+                        None => continue,
+                    }
+                }
+                Block::Outside if parser.is_some() => (line, false),
+                Block::Outside if !pending.is_empty() => {
+                    (line.strip_prefix(self.prefix).unwrap_or(line), false)
+                }
+                Block::Outside => {
+                    let Some(line) = line.strip_prefix(self.prefix) else {
                         continue;
+                    };
+                    match Self::command_line(line, &mut block).map_err(at_line)? {
+                        Some(code) => code,
+                        None => continue,
                     }
-                    (ParseCtx::default(), line[self.prefix.len()..].chars())
                 }
             };
 
+            let mut local_parser = parser.unwrap_or_else(|| ParseCtx {
+                max_depth: self.max_depth,
+                ..Default::default()
+            });
+            // `code` is always a slice of `line`
+            let column = line[..code.as_ptr() as usize - line.as_ptr() as usize]
+                .chars()
+                .count();
+            local_parser.start_line(self.line, column);
+
             if local_parser
-                .push_chars(&mut line_iter.peekable())?
+                .push_chars(&mut code.chars().peekable())?
                 .is_some()
             {
-                do yeet ParseError::IllegalRootUnnest;
+                do yeet at_line(ParseError::IllegalRootUnnest);
             }
 
             match local_parser.close()? {
-                Some(args) => {
-                    shell_commands.push(args);
+                Some(tokens) => {
+                    pending.extend(tokens);
+                    pending.push((
+                        Token::Operator(Operator::Newline),
+                        Span::new(self.line, line_span.len, 0),
+                    ));
+
+                    match CommandList::from_tokens(pending.clone(), self.max_depth) {
+                        Ok(list) => {
+                            if !list.is_empty() {
+                                shell_commands.push(list);
+                            }
+                            pending.clear();
+                        }
+                        Err(Spanned {
+                            error: ParseError::UnexpectedEnd,
+                            ..
+                        }) => {}
+                        Err(err) => do yeet err,
+                    }
                     parser = None;
                 }
                 None => parser = Some(local_parser),
             };
+
+            if script_end && (parser.is_some() || !pending.is_empty()) {
+                do yeet Self::unfinished(&pending, line_span);
+            }
         }
 
-        if parser.is_some() {
-            do yeet ParseError::UnfinishedLastCommand;
+        match block {
+            Block::Outside => {}
+            Block::Awaiting => do yeet Spanned::new(ParseError::ExpectedCodeBlock, block_start),
+            Block::Fence => do yeet Spanned::new(ParseError::UnclosedCodeBlock, block_start),
+        }
+        if parser.is_some() || !pending.is_empty() {
+            do yeet Self::unfinished(&pending, line_span);
         }
 
         Ok(shell_commands)
     }
+
+    /// Points at the start of the unfinished command, or at the last line
+    /// if it's continued with a backslash
+    fn unfinished(pending: &[(Token, Span)], line_span: Span) -> Spanned<ParseError> {
+        let span = pending.first().map_or(line_span, |(_, span)| *span);
+        Spanned::new(ParseError::UnfinishedLastCommand, span)
+    }
+
+    /// A line after the prefix, `run` followed by code is the same as the
+    /// code alone
+    fn command_line(
+        line: &'a str,
+        block: &mut Block,
+    ) -> Result<Option<(&'a str, bool)>, ParseError> {
+        let line = match line.strip_prefix("run") {
+            Some(script) if script.trim().is_empty() => {
+                *block = Block::Awaiting;
+                return Ok(None);
+            }
+            Some(script) if script.starts_with(char::is_whitespace) => script.trim_start(),
+            _ => line,
+        };
+        Self::code(line, block)
+    }
+
+    /// Strips inline code and one line fences, which are whole scripts, or
+    /// opens a fence. Fences only take the language from their first line.
+    fn code(line: &'a str, block: &mut Block) -> Result<Option<(&'a str, bool)>, ParseError> {
+        let ticks = &line[..line.len() - line.trim_start_matches('`').len()];
+
+        match ticks.len() {
+            0 => Ok(Some((line, false))),
+            1 | 2 => {
+                let code = line[ticks.len()..]
+                    .trim_end()
+                    .strip_suffix(ticks)
+                    .ok_or(ParseError::UnclosedCodeBlock)?;
+                Ok(Some((code, true)))
+            }
+            _ => {
+                let code = &line[3..];
+                if let Some(code) = code.trim_end().strip_suffix("```") {
+                    return Ok(Some((code, true)));
+                }
+
+                *block = Block::Fence;
+                if code.trim().contains(char::is_whitespace) {
+                    Ok(Some((code, false)))
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub enum EnvironValue {
+    #[default]
     None,
     String(OsString),
     Blob(Vec<u8>),
     Number(i128),
     UNumber(u128),
+    Array(Vec<EnvironValue>),
+    Map(BTreeMap<String, EnvironValue>),
 }
 
 impl EnvironValue {
+    /// Arrays are space separated and maps are `key=value` lines, values
+    /// nested in them are written as in [`EnvironValue::nested`]
     pub fn as_string(self) -> Option<OsString> {
         match self {
             Self::None => Some(OsString::new()),
             Self::String(value) => Some(value),
             Self::Number(n) => Some(OsString::from(n.to_string())),
             Self::UNumber(n) => Some(OsString::from(n.to_string())),
-            _ => None
+            Self::Blob(_) => None,
+            Self::Array(items) => Some(Self::join_items(items)),
+            Self::Map(entries) => Some(Self::join_entries(entries, "\n")),
+        }
+    }
+
+    /// Arrays in `[a b]` and maps in `{k=v k2=v2}` so the nesting shows,
+    /// blobs only show their size
+    fn nested(self) -> OsString {
+        match self {
+            Self::Blob(blob) => OsString::from(format!("<{} bytes>", blob.len())),
+            Self::Array(items) => {
+                let mut nested = OsString::from("[");
+                nested.push(Self::join_items(items));
+                nested.push("]");
+                nested
+            }
+            Self::Map(entries) => {
+                let mut nested = OsString::from("{");
+                nested.push(Self::join_entries(entries, " "));
+                nested.push("}");
+                nested
+            }
+            value => value.as_string().unwrap_or_default(),
+        }
+    }
+
+    fn join_items(items: Vec<Self>) -> OsString {
+        let items: Vec<_> = items.into_iter().map(Self::nested).collect();
+        items.join(OsStr::new(" "))
+    }
+
+    fn join_entries(entries: BTreeMap<String, Self>, separator: &str) -> OsString {
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|(key, value)| {
+                let mut entry = OsString::from(key + "=");
+                entry.push(value.nested());
+                entry
+            })
+            .collect();
+        entries.join(OsStr::new(separator))
+    }
+}
+
+impl EnvironValue {
+    /// Bytes taken by the value's contents, as checked against
+    /// [`crate::config::Limits::output_bytes`]
+    pub fn size(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::String(value) => value.len(),
+            Self::Blob(blob) => blob.len(),
+            Self::Number(_) | Self::UNumber(_) => size_of::<u128>(),
+            Self::Array(items) => items.iter().map(Self::size).sum(),
+            Self::Map(entries) => entries.iter().map(|(k, v)| k.len() + v.size()).sum(),
+        }
+    }
+
+    /// Whether it's [`EnvironValue::None`] or an empty string/blob/array/map
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::None => true,
+            Self::String(value) => value.is_empty(),
+            Self::Blob(blob) => blob.is_empty(),
+            Self::Number(_) | Self::UNumber(_) => false,
+            Self::Array(items) => items.is_empty(),
+            Self::Map(entries) => entries.is_empty(),
+        }
+    }
+
+    /// Raw bytes of the value, numbers become their decimal representation
+    /// and arrays/maps their [`EnvironValue::as_string`] one
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Self::None => vec![],
+            Self::String(value) => value.into_vec(),
+            Self::Blob(blob) => blob,
+            Self::Number(n) => n.to_string().into_bytes(),
+            Self::UNumber(n) => n.to_string().into_bytes(),
+            value @ (Self::Array(_) | Self::Map(_)) => {
+                value.as_string().unwrap_or_default().into_vec()
+            }
+        }
+    }
+
+    /// Concatenates both values, a [`EnvironValue::Blob`] on either side
+    /// keeps the result a blob, otherwise it's a string. Appending to an
+    /// array pushes the value, maps merge.
+    pub fn append(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::None, other) => other,
+            (this, Self::None) => this,
+            (Self::Array(mut items), other) => {
+                items.push(other);
+                Self::Array(items)
+            }
+            (Self::Map(mut entries), Self::Map(mut other)) => {
+                entries.append(&mut other);
+                Self::Map(entries)
+            }
+            (this @ Self::Blob(_), other) | (this, other @ Self::Blob(_)) => {
+                let mut blob = this.into_bytes();
+                blob.append(&mut other.into_bytes());
+                Self::Blob(blob)
+            }
+            (this, other) => {
+                let mut string = OsString::from_vec(this.into_bytes());
+                string.push(OsString::from_vec(other.into_bytes()));
+                Self::String(string)
+            }
         }
     }
 }
@@ -88,99 +367,961 @@ impl From<&str> for EnvironValue {
     }
 }
 
+/// What a command leaves behind, same idea as [`std::process::Output`]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Output {
+    /// `0` means success, exposed as `$?`
+    pub status: u8,
+    pub stdout: EnvironValue,
+    pub stderr: OsString,
+}
+
+impl Output {
+    pub fn failure(status: u8, stderr: impl Into<OsString>) -> Self {
+        Self {
+            status,
+            stdout: EnvironValue::None,
+            stderr: stderr.into(),
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.status == 0
+    }
+}
+
+impl From<EnvironValue> for Output {
+    fn from(stdout: EnvironValue) -> Self {
+        Self {
+            stdout,
+            ..Default::default()
+        }
+    }
+}
+
+/// Errors don't abort the whole message, they just make the command fail
+impl<E: Display> From<ExecuteError<E>> for Output {
+    fn from(err: ExecuteError<E>) -> Self {
+        Self::failure(1, format!("error: {err}\n"))
+    }
+}
+
+/// What [`Attributes::kind`] restricts a variable to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    Integer,
+    Array,
+    Map,
+}
+
+impl ValueKind {
+    /// Integers can be set from their text, [`EnvironValue::None`] fits
+    /// every kind
+    pub fn coerce(self, value: EnvironValue) -> Option<EnvironValue> {
+        match (self, value) {
+            (_, EnvironValue::None) => Some(EnvironValue::None),
+            (Self::Integer, value @ EnvironValue::Number(_)) => Some(value),
+            (Self::Integer, EnvironValue::UNumber(n)) => {
+                Some(EnvironValue::Number(i128::try_from(n).ok()?))
+            }
+            (Self::Integer, EnvironValue::String(value)) => {
+                Some(EnvironValue::Number(value.to_str()?.trim().parse().ok()?))
+            }
+            (Self::Array, value @ EnvironValue::Array(_)) => Some(value),
+            (Self::Map, value @ EnvironValue::Map(_)) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ValueKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            Self::Integer => "integers",
+            Self::Array => "arrays",
+            Self::Map => "maps",
+        };
+        write!(f, "{kind}")
+    }
+}
+
+/// How a variable can be used, see [`Environ::declare`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Attributes {
+    /// [`Environ::set`] fails
+    pub readonly: bool,
+    /// Listed by commands like `env`, the interpreter's own variables such
+    /// as `?` aren't
+    pub exported: bool,
+    /// Its value is hidden when listed
+    pub secret: bool,
+    pub kind: Option<ValueKind>,
+}
+
+impl Attributes {
+    /// What `set` stores for `value`, or why it can't
+    pub fn check(&self, key: &str, value: EnvironValue) -> Result<EnvironValue, EnvironError> {
+        if self.readonly {
+            do yeet EnvironError::Readonly(key.to_owned());
+        }
+        match self.kind {
+            Some(kind) => kind.coerce(value).ok_or(EnvironError::WrongType {
+                name: key.to_owned(),
+                kind,
+            }),
+            None => Ok(value),
+        }
+    }
+
+    /// Attributes only get added, a kind can't be changed once readonly
+    pub fn merge(self, key: &str, other: Self) -> Result<Self, EnvironError> {
+        if self.readonly && other.kind.is_some_and(|kind| self.kind != Some(kind)) {
+            do yeet EnvironError::Readonly(key.to_owned());
+        }
+        Ok(Self {
+            readonly: self.readonly || other.readonly,
+            exported: self.exported || other.exported,
+            secret: self.secret || other.secret,
+            kind: other.kind.or(self.kind),
+        })
+    }
+
+    /// Whether variables can be declared as `key`, positional parameters
+    /// and the likes stay the interpreter's
+    pub fn can_declare(key: &str) -> bool {
+        ParamExpansion::is_name(key) && key != "?" && !key.starts_with(|c: char| c.is_ascii_digit())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvironError {
+    Readonly(String),
+    WrongType { name: String, kind: ValueKind },
+    InvalidName(String),
+}
+
+impl Display for EnvironError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Readonly(name) => write!(f, "`{name}` is readonly"),
+            Self::WrongType { name, kind } => write!(f, "`{name}` only holds {kind}"),
+            Self::InvalidName(name) => write!(f, "`{name}` can't be declared"),
+        }
+    }
+}
+
+/// Builtins setting variables fail with it too, so it's a command error
+impl super::CommandError for EnvironError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Readonly(_) => "E421",
+            Self::WrongType { .. } => "E422",
+            Self::InvalidName(_) => "E423",
+        }
+    }
+}
+
 pub trait Environ<'a> {
-    fn get(&self, key: &str) -> Option<&EnvironValue>;
-    fn set(&mut self, key: String, value: EnvironValue) -> Option<EnvironValue>;
+    /// Owned for values resolved on every read, as `$RANDOM`
+    fn get(&self, key: &str) -> Option<Cow<'_, EnvironValue>>;
+    /// Fails as in [`Attributes::check`]
+    fn set(
+        &mut self,
+        key: String,
+        value: EnvironValue,
+    ) -> Result<Option<EnvironValue>, EnvironError>;
+
+    /// Fails if `key` is readonly, its other attributes stay
+    fn remove(&mut self, key: &str) -> Result<Option<EnvironValue>, EnvironError>;
 
     fn entries(&self) -> impl Iterator<Item = (&str, &EnvironValue)>;
+
+    fn attributes(&self, key: &str) -> Attributes;
+    /// Adds `attributes` to `key`, its current value is coerced into the
+    /// new kind if there's one
+    fn declare(&mut self, key: String, attributes: Attributes) -> Result<(), EnvironError>;
+
+    /// `>>` into `key`, see [`EnvironValue::append`]
+    fn append(
+        &mut self,
+        key: String,
+        value: EnvironValue,
+    ) -> Result<Option<EnvironValue>, EnvironError> {
+        let new_value = self
+            .get(&key)
+            .map(Cow::into_owned)
+            .unwrap_or_default()
+            .append(value);
+        self.set(key, new_value)
+    }
 }
 
 pub trait Executer<E> {
+    /// `input` is the output of the previous command in the pipeline, or
+    /// [`EnvironValue::None`] for the first one
     fn execute<'a>(
         &mut self,
         args: Vec<EnvironValue>,
-        env: &mut impl Environ<'a>,
-    ) -> Result<EnvironValue, E>;
+        input: EnvironValue,
+        env: &mut (impl Environ<'a> + Send),
+    ) -> impl Future<Output = Result<Output, E>> + Send;
+
+    /// Whether `name` may run, for the builtins the interpreter runs itself
+    /// instead of passing them to [`Executer::execute`]
+    fn check(&self, _name: &str) -> Result<(), E> {
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ExecuteError<E> {
-    NoSuchEnv,
+    NoSuchEnv(String),
     ExecuterError(E),
     UnserializableValue,
+    InvalidEnvName,
+    Arithmetic(arithmetic::ArithmeticError),
+    /// The alias doesn't parse, or can't take arguments
+    InvalidAlias(String),
+    Environ(EnvironError),
+    LimitExceeded(Limit),
 }
 
-#[derive(Debug, Default, Clone, PartialEq, Eq)]
-pub struct ShellArgs(Vec<Vec<ShellArg>>);
+/// One of [`crate::config::Limits`] that was hit, with its value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    ExpandedArgs(usize),
+    OutputBytes(usize),
+    Commands(usize),
+    RunDepth(usize),
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ExpandedArgs(max) => write!(f, "command expanded to more than {max} args"),
+            Self::OutputBytes(max) => write!(
+                f,
+                "value larger than {}",
+                sizes::bytes_to_binary(*max as f64, 2)
+            ),
+            Self::Commands(max) => write!(f, "message ran more than {max} commands"),
+            Self::RunDepth(max) => write!(f, "ran nested deeper than {max} levels"),
+        }
+    }
+}
+
+impl Diagnostic for Limit {
+    fn code(&self) -> Option<&'static str> {
+        Some(match self {
+            Self::ExpandedArgs(_) => "E431",
+            Self::OutputBytes(_) => "E432",
+            Self::Commands(_) => "E433",
+            Self::RunDepth(_) => "E434",
+        })
+    }
+
+    fn hint(&self) -> Option<String> {
+        Some(String::from("the bot's owners can raise it in `[limits]`"))
+    }
+}
+
+impl<E: Display> Display for ExecuteError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoSuchEnv(name) => write!(f, "no such variable `{name}`"),
+            Self::ExecuterError(err) => write!(f, "{err}"),
+            Self::UnserializableValue => write!(f, "value can't be turned into text"),
+            Self::InvalidEnvName => write!(f, "invalid variable name"),
+            Self::Arithmetic(err) => write!(f, "{err}"),
+            Self::InvalidAlias(name) => write!(f, "alias `{name}` can't be expanded here"),
+            Self::Environ(err) => write!(f, "{err}"),
+            Self::LimitExceeded(limit) => write!(f, "{limit}"),
+        }
+    }
+}
+
+impl<E: Diagnostic> Diagnostic for ExecuteError<E> {
+    fn code(&self) -> Option<&'static str> {
+        match self {
+            Self::NoSuchEnv(_) => Some("E401"),
+            Self::ExecuterError(err) => err.code(),
+            Self::UnserializableValue => Some("E402"),
+            Self::InvalidEnvName => Some("E403"),
+            Self::Arithmetic(err) => err.code(),
+            Self::InvalidAlias(_) => Some("E404"),
+            Self::Environ(err) => err.code(),
+            Self::LimitExceeded(limit) => limit.code(),
+        }
+    }
+
+    fn hint(&self) -> Option<String> {
+        match self {
+            Self::ExecuterError(err) => err.hint(),
+            Self::Arithmetic(err) => err.hint(),
+            Self::Environ(err) => err.hint(),
+            Self::LimitExceeded(limit) => limit.hint(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShellArgs {
+    pub args: Vec<Vec<ShellArg>>,
+    pub redirects: Vec<Redirect>,
+    /// Where each of `args` is, stored definitions don't keep them
+    #[serde(skip)]
+    pub spans: Vec<Span>,
+    /// The whole command
+    #[serde(skip)]
+    pub span: Span,
+}
+
+/// Redirections from/into env variables, targets resolve to the var name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Redirect {
+    /// `< VAR`
+    Input(Vec<ShellArg>),
+    /// `> VAR`
+    Output(Vec<ShellArg>),
+    /// `>> VAR`
+    Append(Vec<ShellArg>),
+}
 
 impl ShellArgs {
-    pub fn resolve<'a, E>(
-        self,
-        environ: &mut impl Environ<'a>,
-        executer: &mut impl Executer<E>,
-    ) -> Result<EnvironValue, ExecuteError<E>> {
-        let arg_list: Vec<_> = self
-            .0
-            .into_iter()
-            .map(|mut arg| -> Result<EnvironValue, ExecuteError<E>> {
-                if arg.len() <= 1 {
-                    if let ShellArg::EnvVar(value) = &arg[0] {
-                        return Ok(environ.get(&value).ok_or(ExecuteError::NoSuchEnv)?.clone());
-                    };
-                    if let ShellArg::Subshell(_) = &arg[0] {
-                        if let ShellArg::Subshell(args) = arg.remove(0) {
-                            return args.resolve(&mut *environ, executer);
-                        };
-                    };
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty() && self.redirects.is_empty()
+    }
+}
+
+/// Anything that can be a stage of a [`Pipeline`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    Simple(ShellArgs),
+    /// `if list; then list; [elif list; then list;]... [else list;] fi`
+    If {
+        branches: Vec<(CommandList, CommandList)>,
+        otherwise: Option<CommandList>,
+        /// The `if` keyword
+        #[serde(skip)]
+        span: Span,
+    },
+    /// `for NAME in word...; do list; done`
+    For {
+        var: String,
+        words: Vec<Vec<ShellArg>>,
+        body: CommandList,
+        /// The `for` keyword
+        #[serde(skip)]
+        span: Span,
+    },
+    /// `while list; do list; done`, or `until` if `until`
+    While {
+        cond: CommandList,
+        body: CommandList,
+        until: bool,
+        /// The `while` or `until` keyword
+        #[serde(skip)]
+        span: Span,
+    },
+    /// `case word in pattern|pattern) list;; ... esac`
+    Case {
+        word: Vec<ShellArg>,
+        items: Vec<(Vec<Vec<ShellArg>>, CommandList)>,
+        /// The matched word
+        #[serde(skip)]
+        span: Span,
+    },
+    /// `function name { list; }`, running it defines the function
+    Function {
+        name: String,
+        body: CommandList,
+    },
+}
+
+/// Commands chained with `|`, each one gets the previous one's output as its
+/// input
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pipeline(pub Vec<Command>);
+
+/// Pipelines chained with `;`, `&&` or `||`. Each one is paired with the
+/// operator joining it to the previous one, the first is always
+/// [`Operator::Sequence`]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandList(pub Vec<(Operator, Pipeline)>);
+
+impl CommandList {
+    /// `max_depth` limits how deep blocks like `if` nest
+    pub fn from_tokens(
+        tokens: Vec<(Token, Span)>,
+        max_depth: Option<usize>,
+    ) -> Result<Self, Spanned<ParseError>> {
+        let mut grammar = Grammar {
+            tokens: tokens.into_iter().peekable(),
+            span: Span::default(),
+            depth: 0,
+            max_depth,
+        };
+        let list = grammar.list(&[]).and_then(|list| match grammar.take() {
+            None => Ok(list),
+            Some(token) => Err(Grammar::unexpected(token)),
+        });
+
+        list.map_err(|err| Spanned::new(err, grammar.span))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Valid name for functions and aliases
+pub fn is_command_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Words taken as keywords at the start of a command
+pub fn is_keyword(word: &str) -> bool {
+    Grammar::RESERVED.contains(&word)
+        || ["if", "for", "while", "until", "case", "function"].contains(&word)
+}
+
+/// Recursive descent over [`Token`]s, keywords are only recognized at the
+/// start of a command
+struct Grammar {
+    tokens: Peekable<std::vec::IntoIter<(Token, Span)>>,
+    /// Span of the last token taken, errors point at it
+    span: Span,
+    /// Blocks the current command is in
+    depth: usize,
+    max_depth: Option<usize>,
+}
+
+impl Grammar {
+    /// Keywords that can't start a command
+    const RESERVED: &'static [&'static str] =
+        &["then", "elif", "else", "fi", "do", "done", "esac", "}"];
+
+    fn unexpected(token: Token) -> ParseError {
+        match token {
+            Token::Operator(op) => ParseError::UnexpectedOperator(op),
+            token => ParseError::UnexpectedToken(token),
+        }
+    }
+
+    /// Running out of tokens inside a compound command is
+    /// [`ParseError::UnexpectedEnd`], so the block can go on the next line
+    fn next(&mut self) -> Result<Token, ParseError> {
+        self.take().ok_or(ParseError::UnexpectedEnd)
+    }
+
+    fn take(&mut self) -> Option<Token> {
+        let (token, span) = self.tokens.next()?;
+        self.span = span;
+        Some(token)
+    }
+
+    fn peek(&mut self) -> Option<&Token> {
+        self.tokens.peek().map(|(token, _)| token)
+    }
+
+    /// Takes the next token if it's `op`
+    fn next_if_operator(&mut self, op: Operator) -> bool {
+        let found = self.peek() == Some(&Token::Operator(op));
+        if found {
+            self.take();
+        }
+        found
+    }
+
+    fn next_word(&mut self) -> Option<Vec<ShellArg>> {
+        let Some(Token::Word(_)) = self.peek() else {
+            return None;
+        };
+        match self.take() {
+            Some(Token::Word(word)) => Some(word),
+            _ => None,
+        }
+    }
+
+    fn peek_keyword(&mut self) -> Option<String> {
+        self.peek().and_then(Token::keyword)
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        let token = self.next()?;
+        if token.keyword().as_deref() != Some(keyword) {
+            do yeet Self::unexpected(token);
+        }
+        Ok(())
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.next_if_operator(Operator::Newline) {}
+    }
+
+    /// Stops before one of the `terminators` keywords, a `;;` or the end
+    fn list(&mut self, terminators: &[&str]) -> Result<CommandList, ParseError> {
+        let mut list = CommandList::default();
+        let mut op = Operator::Sequence;
+
+        loop {
+            if op == Operator::Sequence {
+                self.skip_newlines();
+                match self.peek() {
+                    None | Some(Token::Operator(Operator::CaseBreak)) => break,
+                    Some(token) => {
+                        if token
+                            .keyword()
+                            .is_some_and(|keyword| terminators.contains(&keyword.as_str()))
+                        {
+                            break;
+                        }
+                    }
                 }
+            }
 
-                let mut arg_string = OsString::new();
-                arg.into_iter()
-                    .try_for_each(|component| -> Result<(), ExecuteError<E>> {
-                        match component {
-                            ShellArg::Byte(byte) => arg_string.push(OsString::from_vec(vec![byte])),
-                            ShellArg::Char(ch) => arg_string.push(ch.to_string()),
-                            ShellArg::RawString(rstring) => arg_string.push(rstring),
-                            ShellArg::String(string) => arg_string.push(string),
-                            ShellArg::EnvVar(env_ref) => arg_string.push(
-                                environ
-                                    .get(&env_ref)
-                                    .ok_or(ExecuteError::NoSuchEnv)?
-                                    .clone()
-                                    .as_string()
-                                    .ok_or(ExecuteError::UnserializableValue)?,
-                            ),
-                            ShellArg::Subshell(args) => arg_string.push(
-                                args.resolve(&mut *environ, executer)?
-                                    .as_string()
-                                    .ok_or(ExecuteError::UnserializableValue)?,
-                            ),
-                        };
-                        Ok(())
-                    })?;
-                Ok(EnvironValue::String(arg_string))
-            })
-            .try_collect()?;
+            list.0.push((op, self.pipeline()?));
+
+            op = match self.peek() {
+                Some(Token::Operator(Operator::Newline)) => Operator::Sequence,
+                Some(Token::Operator(op @ (Operator::Sequence | Operator::And | Operator::Or))) => {
+                    *op
+                }
+                _ => break,
+            };
+            self.take();
+        }
+
+        Ok(list)
+    }
+
+    fn nonempty_list(&mut self, terminators: &[&str]) -> Result<CommandList, ParseError> {
+        let list = self.list(terminators)?;
+        if list.is_empty() {
+            do yeet Self::unexpected(self.next()?);
+        }
+        Ok(list)
+    }
+
+    fn pipeline(&mut self) -> Result<Pipeline, ParseError> {
+        let mut pipeline = Pipeline(vec![self.command()?]);
+        while self.next_if_operator(Operator::Pipe) {
+            self.skip_newlines();
+            pipeline.0.push(self.command()?);
+        }
+        Ok(pipeline)
+    }
+
+    fn command(&mut self) -> Result<Command, ParseError> {
+        let clause = match self.peek_keyword().as_deref() {
+            Some("if") => Self::if_clause,
+            Some("for") => Self::for_clause,
+            Some("while") => |grammar: &mut Self| grammar.while_clause(false),
+            Some("until") => |grammar: &mut Self| grammar.while_clause(true),
+            Some("case") => Self::case_clause,
+            Some("function") => Self::function_def,
+            Some(keyword) if Self::RESERVED.contains(&keyword) => {
+                do yeet Self::unexpected(self.next()?)
+            }
+            _ => return self.simple_command(),
+        };
+
+        if let Some(max_depth) = self.max_depth.filter(|max_depth| self.depth >= *max_depth) {
+            self.take();
+            do yeet ParseError::NestingTooDeep(max_depth);
+        }
+        self.depth += 1;
+        let command = clause(self);
+        self.depth -= 1;
+        command
+    }
+
+    fn simple_command(&mut self) -> Result<Command, ParseError> {
+        let mut args = ShellArgs::default();
+        let start = self.tokens.peek().map(|(_, span)| *span);
+
+        loop {
+            if let Some(arg) = self.next_word() {
+                args.args.push(arg);
+                args.spans.push(self.span);
+                continue;
+            }
+
+            let redirect: fn(Vec<ShellArg>) -> Redirect = match self.peek() {
+                Some(Token::Operator(Operator::RedirectInput)) => Redirect::Input,
+                Some(Token::Operator(Operator::RedirectOutput)) => Redirect::Output,
+                Some(Token::Operator(Operator::RedirectAppend)) => Redirect::Append,
+                _ => break,
+            };
+            self.take();
+
+            match self.take() {
+                Some(Token::Word(target)) => args.redirects.push(redirect(target)),
+                None | Some(Token::Operator(Operator::Newline)) => {
+                    do yeet ParseError::MissingRedirectTarget
+                }
+                Some(token) => do yeet Self::unexpected(token),
+            }
+        }
+
+        if args.is_empty() {
+            do yeet Self::unexpected(self.next()?);
+        }
+        args.span = start.unwrap_or_default().to(self.span);
+        Ok(Command::Simple(args))
+    }
+
+    fn if_clause(&mut self) -> Result<Command, ParseError> {
+        self.expect_keyword("if")?;
+        let span = self.span;
+        let mut branches = vec![];
+
+        loop {
+            let cond = self.nonempty_list(&["then"])?;
+            self.expect_keyword("then")?;
+            branches.push((cond, self.nonempty_list(&["elif", "else", "fi"])?));
+
+            let token = self.next()?;
+            match token.keyword().as_deref() {
+                Some("elif") => {}
+                Some("else") => {
+                    let otherwise = self.nonempty_list(&["fi"])?;
+                    self.expect_keyword("fi")?;
+                    return Ok(Command::If {
+                        branches,
+                        otherwise: Some(otherwise),
+                        span,
+                    });
+                }
+                Some("fi") => {
+                    return Ok(Command::If {
+                        branches,
+                        otherwise: None,
+                        span,
+                    });
+                }
+                _ => do yeet Self::unexpected(token),
+            }
+        }
+    }
+
+    fn for_clause(&mut self) -> Result<Command, ParseError> {
+        self.expect_keyword("for")?;
+        let span = self.span;
+        let token = self.next()?;
+        let Some(var) = token
+            .keyword()
+            .filter(|var| var != "?" && ParamExpansion::is_name(var))
+        else {
+            do yeet Self::unexpected(token);
+        };
+        self.expect_keyword("in")?;
+
+        let mut words = vec![];
+        while let Some(word) = self.next_word() {
+            words.push(word);
+        }
+        match self.next()? {
+            Token::Operator(Operator::Sequence | Operator::Newline) => {}
+            token => do yeet Self::unexpected(token),
+        }
+
+        let body = self.do_group()?;
+        Ok(Command::For {
+            var,
+            words,
+            body,
+            span,
+        })
+    }
+
+    fn while_clause(&mut self, until: bool) -> Result<Command, ParseError> {
+        self.next()?;
+        let span = self.span;
+        let cond = self.nonempty_list(&["do"])?;
+        let body = self.do_group()?;
+        Ok(Command::While {
+            cond,
+            body,
+            until,
+            span,
+        })
+    }
+
+    /// `do list; done`
+    fn do_group(&mut self) -> Result<CommandList, ParseError> {
+        self.skip_newlines();
+        self.expect_keyword("do")?;
+        let body = self.nonempty_list(&["done"])?;
+        self.expect_keyword("done")?;
+        Ok(body)
+    }
+
+    fn function_def(&mut self) -> Result<Command, ParseError> {
+        self.expect_keyword("function")?;
+        let token = self.next()?;
+        let Some(name) = token.keyword().filter(|name| is_command_name(name)) else {
+            do yeet Self::unexpected(token);
+        };
 
-        executer
-            .execute(arg_list, environ)
-            .map_err(ExecuteError::ExecuterError)
+        self.skip_newlines();
+        self.expect_keyword("{")?;
+        let body = self.nonempty_list(&["}"])?;
+        self.expect_keyword("}")?;
+        Ok(Command::Function { name, body })
+    }
+
+    fn case_clause(&mut self) -> Result<Command, ParseError> {
+        self.expect_keyword("case")?;
+        let word = match self.next()? {
+            Token::Word(word) => word,
+            token => do yeet Self::unexpected(token),
+        };
+        let span = self.span;
+        self.skip_newlines();
+        self.expect_keyword("in")?;
+
+        let mut items = vec![];
+        loop {
+            self.skip_newlines();
+            if self.peek_keyword().as_deref() == Some("esac") {
+                self.next()?;
+                return Ok(Command::Case { word, items, span });
+            }
+
+            let mut patterns = vec![];
+            loop {
+                match self.next()? {
+                    Token::Word(pattern) => patterns.push(pattern),
+                    token => do yeet Self::unexpected(token),
+                }
+                match self.next()? {
+                    Token::Operator(Operator::Pipe) => {}
+                    Token::Operator(Operator::CloseParen) => break,
+                    token => do yeet Self::unexpected(token),
+                }
+            }
+
+            // the last item doesn't need its `;;`
+            items.push((patterns, self.list(&["esac"])?));
+            self.next_if_operator(Operator::CaseBreak);
+        }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ShellArg {
     Byte(u8),
     Char(char),
     RawString(OsString),
     String(String),
     EnvVar(String),
-    Subshell(ShellArgs),
+    Parameter(ParamExpansion),
+    Arithmetic(arithmetic::Expr),
+    Subshell(CommandList),
+}
+
+impl ShellArg {
+    /// The word as a plain string if it's only made of chars, without any
+    /// expansion
+    pub fn literal(word: &[Self]) -> Option<String> {
+        word.iter()
+            .map(|component| match component {
+                Self::Char(ch) => Some(*ch),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// `${...}` forms other than a plain `${NAME}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParamExpansion {
+    /// `${NAME:-word}`, or `${NAME:=word}` if `assign`
+    Default {
+        name: String,
+        word: Vec<ShellArg>,
+        assign: bool,
+    },
+    /// `${#NAME}`
+    Length(String),
+    /// `${NAME:offset}` or `${NAME:offset:length}`, negative values count
+    /// from the end
+    Substring {
+        name: String,
+        offset: i64,
+        length: Option<i64>,
+    },
+    /// `${NAME/pattern/replacement}`, or every match if `all` (`//`)
+    Replace {
+        name: String,
+        pattern: Vec<ShellArg>,
+        replacement: Vec<ShellArg>,
+        all: bool,
+    },
+    /// `${NAME[index]}`, an array element or a map value
+    Index { name: String, index: Vec<ShellArg> },
+    /// `${NAME[@]}` or `$@`, as a whole word every element is its own arg
+    Splat(String),
+}
+
+impl ParamExpansion {
+    /// Parses the contents between `${` and `}`
+    pub fn parse(expr: &str) -> Result<ShellArg, ParseError> {
+        if expr == "@" {
+            return Ok(ShellArg::Parameter(Self::Splat(expr.to_owned())));
+        }
+        if let Some(name) = expr.strip_prefix('#') {
+            let name = name.strip_suffix("[@]").unwrap_or(name);
+            if !Self::is_name(name) {
+                do yeet ParseError::BadSubstitution;
+            }
+            return Ok(ShellArg::Parameter(Self::Length(name.to_owned())));
+        }
+
+        let name_len = if expr.starts_with('?') {
+            1
+        } else {
+            expr.find(|c: char| !c.is_alphanumeric() && c != '_')
+                .unwrap_or(expr.len())
+        };
+        let (name, op) = expr.split_at(name_len);
+        if name.is_empty() {
+            do yeet ParseError::BadSubstitution;
+        }
+        let name = name.to_owned();
+
+        let param = if op.is_empty() {
+            return Ok(ShellArg::EnvVar(name));
+        } else if let Some(index) = op.strip_prefix('[').and_then(|op| op.strip_suffix(']')) {
+            if index == "@" {
+                Self::Splat(name)
+            } else {
+                Self::Index {
+                    name,
+                    index: ParseCtx::word_from_chars(&mut index.chars())
+                        .map_err(|err| err.error)?,
+                }
+            }
+        } else if let Some(word) = op.strip_prefix(":-") {
+            Self::Default {
+                name,
+                word: ParseCtx::word_from_chars(&mut word.chars()).map_err(|err| err.error)?,
+                assign: false,
+            }
+        } else if let Some(word) = op.strip_prefix(":=") {
+            Self::Default {
+                name,
+                word: ParseCtx::word_from_chars(&mut word.chars()).map_err(|err| err.error)?,
+                assign: true,
+            }
+        } else if let Some(range) = op.strip_prefix(':') {
+            let (offset, length) = match range.split_once(':') {
+                Some((offset, length)) => (offset, Some(length)),
+                None => (range, None),
+            };
+            let parse_int = |n: &str| n.trim().parse().map_err(|_| ParseError::BadSubstitution);
+
+            Self::Substring {
+                name,
+                offset: parse_int(offset)?,
+                length: length.map(parse_int).transpose()?,
+            }
+        } else if let Some(op) = op.strip_prefix('/') {
+            let (all, op) = match op.strip_prefix('/') {
+                Some(op) => (true, op),
+                None => (false, op),
+            };
+            let (pattern, replacement) = op.split_once('/').unwrap_or((op, ""));
+
+            Self::Replace {
+                name,
+                pattern: ParseCtx::word_from_chars(&mut pattern.chars())
+                    .map_err(|err| err.error)?,
+                replacement: ParseCtx::word_from_chars(&mut replacement.chars())
+                    .map_err(|err| err.error)?,
+                all,
+            }
+        } else {
+            do yeet ParseError::BadSubstitution;
+        };
+
+        Ok(ShellArg::Parameter(param))
+    }
+
+    pub fn is_name(name: &str) -> bool {
+        name == "?" || (!name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_'))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operator {
+    /// `|`
+    Pipe,
+    /// `;`
+    Sequence,
+    /// `&&`
+    And,
+    /// `||`
+    Or,
+    /// `<`
+    RedirectInput,
+    /// `>`
+    RedirectOutput,
+    /// `>>`
+    RedirectAppend,
+    /// End of a line, separates commands like `;`
+    Newline,
+    /// `;;`, ends a `case` item
+    CaseBreak,
+    /// `)`, ends a `case` pattern
+    CloseParen,
+}
+
+impl Display for Operator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let op = match self {
+            Self::Pipe => "|",
+            Self::Sequence => ";",
+            Self::And => "&&",
+            Self::Or => "||",
+            Self::RedirectInput => "<",
+            Self::RedirectOutput => ">",
+            Self::RedirectAppend => ">>",
+            Self::Newline => "\\n",
+            Self::CaseBreak => ";;",
+            Self::CloseParen => ")",
+        };
+        write!(f, "{op}")
+    }
+}
+
+/// Flat output of [`ParseCtx`], later arranged into a [`CommandList`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Word(Vec<ShellArg>),
+    Operator(Operator),
+}
+
+impl Token {
+    /// Keywords like `if` or `done` are matched against
+    /// [`ShellArg::literal`]
+    pub fn keyword(&self) -> Option<String> {
+        match self {
+            Self::Word(arg) => ShellArg::literal(arg),
+            Self::Operator(_) => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ParseAction {
     Separator,
+    Operator(Operator),
     Push(ShellArg),
     Unnest,
     Nest(ParseCtxType),
@@ -193,7 +1334,74 @@ pub enum ParseError {
     IllegalRootUnnest,
     InvalidEscapeSequence,
     UnexpectedCloser(char),
+    UnexpectedOperator(Operator),
+    UnexpectedToken(Token),
+    MissingRedirectTarget,
+    BadSubstitution,
+    InvalidArithmetic,
+    /// Tokens ran out inside an `if`, `for`... block
+    UnexpectedEnd,
+    /// A brace expression would make more than [`brace::MAX_EXPANSION`]
+    /// words
+    BraceExpansionTooLarge,
     UnfinishedLastCommand,
+    /// `run` wasn't followed by inline code or a fence
+    ExpectedCodeBlock,
+    UnclosedCodeBlock,
+    /// Nested deeper than the given limit, see [`MsgParser::max_depth`]
+    NestingTooDeep(usize),
+}
+
+impl Diagnostic for ParseError {
+    fn code(&self) -> Option<&'static str> {
+        Some(match self {
+            Self::IllegalRootUnnest => "E301",
+            Self::InvalidEscapeSequence => "E302",
+            Self::UnexpectedCloser(_) => "E303",
+            Self::UnexpectedOperator(_) => "E304",
+            Self::UnexpectedToken(_) => "E305",
+            Self::MissingRedirectTarget => "E306",
+            Self::BadSubstitution => "E307",
+            Self::InvalidArithmetic => "E308",
+            Self::UnexpectedEnd => "E309",
+            Self::BraceExpansionTooLarge => "E310",
+            Self::UnfinishedLastCommand => "E311",
+            Self::ExpectedCodeBlock => "E312",
+            Self::UnclosedCodeBlock => "E313",
+            Self::NestingTooDeep(_) => "E314",
+        })
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IllegalRootUnnest => write!(f, "unbalanced quote or parenthesis"),
+            Self::InvalidEscapeSequence => write!(f, "invalid escape sequence"),
+            Self::UnexpectedCloser(closer) => write!(f, "unexpected `{closer}`"),
+            Self::UnexpectedOperator(Operator::Newline) => write!(f, "unexpected end of line"),
+            Self::UnexpectedOperator(op) => write!(f, "unexpected `{op}`"),
+            Self::UnexpectedToken(token) => match token.keyword() {
+                Some(keyword) => write!(f, "unexpected `{keyword}`"),
+                None => write!(f, "unexpected word"),
+            },
+            Self::MissingRedirectTarget => write!(f, "missing redirection target"),
+            Self::BadSubstitution => write!(f, "bad substitution"),
+            Self::InvalidArithmetic => write!(f, "invalid arithmetic expression"),
+            Self::UnexpectedEnd => write!(f, "unexpected end of script"),
+            Self::BraceExpansionTooLarge => write!(
+                f,
+                "brace expansion makes more than {} words",
+                brace::MAX_EXPANSION
+            ),
+            Self::UnfinishedLastCommand => write!(f, "unfinished command"),
+            Self::ExpectedCodeBlock => {
+                write!(f, "expected inline code or a code block after `run`")
+            }
+            Self::UnclosedCodeBlock => write!(f, "unclosed code block"),
+            Self::NestingTooDeep(max_depth) => write!(f, "nested deeper than {max_depth} levels"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -202,6 +1410,9 @@ pub enum ParseCtxType {
     Normal(bool),
     /// Bool represents strict (same as single quote in bash)
     Quote(bool),
+    /// Single unclosable word, quotes nest but spaces don't split, used for
+    /// operands inside `${...}`
+    Word,
 }
 impl Default for ParseCtxType {
     fn default() -> Self {
@@ -219,13 +1430,27 @@ pub struct ParseCtx {
     pub typ: ParseCtxType,
     escape: String,
     arg: Vec<ShellArg>,
-    args: ShellArgs,
+    /// Set when `arg` contains a (maybe empty) quote, so it counts as an arg
+    quoted: bool,
+    /// Indexes in `arg` of the `{`, `,` and `}` that can expand, see
+    /// [`brace::expand`]
+    braces: Vec<usize>,
+    tokens: Vec<(Token, Span)>,
     nesting: Box<Option<Self>>,
-    just_separated: bool,
+    /// How many contexts this one is nested in
+    depth: usize,
+    max_depth: Option<usize>,
+    /// Position of the next char, only kept by the root context
+    line: usize,
+    column: usize,
+    escape_start: Span,
+    word_start: Option<Span>,
+    nesting_start: Span,
 }
 
 impl ParseCtxType {
-    const ESCAPABLE_CHARS: &'static [char] = &['\\', ' ', '(', ')', '{', '}'];
+    const ESCAPABLE_CHARS: &'static [char] =
+        &['\\', ' ', '(', ')', '{', '}', '|', '&', ';', '<', '>'];
     const ESCAPABLE_BYTES: &'static [(char, char)] =
         &[('n', '\n'), ('r', '\r'), ('t', '\t'), ('0', '\0')];
 
@@ -235,13 +1460,20 @@ impl ParseCtxType {
             Self::Normal(false) => ')',
             Self::Quote(true) => '\'',
             Self::Quote(false) => '"',
+            Self::Word => '}',
         }
     }
 
     pub fn token(&self, token: char) -> Result<ParseAction, ParseError> {
         match (self, token) {
             (Self::Normal(_), ' ') => Ok(ParseAction::Separator),
-            (Self::Normal(_), '\'' | '"') => Ok(ParseAction::Nest(Self::Quote(token == '\''))),
+            (Self::Normal(_), '<') => Ok(ParseAction::Operator(Operator::RedirectInput)),
+            (Self::Normal(_), ';' | '|' | '&' | '>') => Ok(ParseAction::EscapeSequence),
+            // only closes `case` patterns, there's nothing to unnest at root
+            (Self::Normal(true), ')') => Ok(ParseAction::Operator(Operator::CloseParen)),
+            (Self::Normal(_) | Self::Word, '\'' | '"') => {
+                Ok(ParseAction::Nest(Self::Quote(token == '\'')))
+            }
             (Self::Quote(_), ' ') => Ok(ParseAction::Push(ShellArg::Char(token))),
             (_, '\\' | '$') => Ok(ParseAction::EscapeSequence),
             (_, '\n' | '\'' | '"' | ')') => {
@@ -258,8 +1490,48 @@ impl ParseCtxType {
         }
     }
 
+    fn paren_depth(expr: &str) -> isize {
+        expr.chars().fold(0, |depth, c| match c {
+            '(' => depth + 1,
+            ')' => depth - 1,
+            _ => depth,
+        })
+    }
+
+    /// Unclosed `{` count, ignoring quoted or escaped ones
+    fn brace_depth(expr: &str) -> isize {
+        let mut depth = 0;
+        let mut quote = None;
+        let mut chars = expr.chars();
+
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+                (Some('\''), _) => {}
+                (_, '\\') => {
+                    // escaping the char that's about to be pushed
+                    if chars.next().is_none() {
+                        return depth + 1;
+                    }
+                }
+                (Some(_), _) => {}
+                (None, '\'' | '"') => quote = Some(c),
+                (None, '{') => depth += 1,
+                (None, '}') => depth -= 1,
+                _ => {}
+            }
+        }
+
+        depth + quote.is_some() as isize
+    }
+
     // bool represents if the new character should be re-enqueed
-    pub fn escape(&self, escape: &str, new_char: char) -> Result<(ParseAction, bool), ParseError> {
+    pub fn escape(
+        &self,
+        escape: &str,
+        new_char: char,
+        max_depth: Option<usize>,
+    ) -> Result<(ParseAction, bool), ParseError> {
         let mut chars_iter = escape.chars();
         let Some(escape_type) = chars_iter.next() else {
             do yeet ParseError::InvalidEscapeSequence;
@@ -268,7 +1540,7 @@ impl ParseCtxType {
         match escape_type {
             '\\' => {
                 let Some(discriminator) = chars_iter.next() else {
-                    if Self::ESCAPABLE_CHARS.iter().any(|&c| c == new_char) {
+                    if Self::ESCAPABLE_CHARS.contains(&new_char) {
                         return Ok((ParseAction::Push(ShellArg::Char(new_char)), false));
                     }
 
@@ -325,16 +1597,43 @@ impl ParseCtxType {
                 };
 
                 if discriminator == '(' {
-                    Ok((ParseAction::Nest(Self::Normal(false)), true))
+                    match chars_iter.next() {
+                        // `$((`, collect until the matching `))`
+                        Some(_) => {
+                            if new_char == ')' && Self::paren_depth(&escape[1..]) == 1 {
+                                let expr = escape[3..]
+                                    .strip_suffix(')')
+                                    .ok_or(ParseError::InvalidArithmetic)?;
+                                Ok((
+                                    ParseAction::Push(ShellArg::Arithmetic(
+                                        arithmetic::Expr::parse(expr, max_depth)?,
+                                    )),
+                                    false,
+                                ))
+                            } else {
+                                Ok((ParseAction::EscapeSequence, false))
+                            }
+                        }
+                        None if new_char == '(' => Ok((ParseAction::EscapeSequence, false)),
+                        None => Ok((ParseAction::Nest(Self::Normal(false)), true)),
+                    }
                 } else if discriminator == '{' {
-                    if new_char == '}' {
-                        Ok((
-                            ParseAction::Push(ShellArg::EnvVar(chars_iter.collect())),
-                            false,
-                        ))
+                    let expr = chars_iter.as_str();
+
+                    if new_char == '}' && Self::brace_depth(expr) == 0 {
+                        Ok((ParseAction::Push(ParamExpansion::parse(expr)?), false))
                     } else {
                         Ok((ParseAction::EscapeSequence, false))
                     }
+                } else if discriminator == '?' {
+                    Ok((ParseAction::Push(ShellArg::EnvVar(String::from("?"))), true))
+                } else if discriminator == '@' {
+                    Ok((
+                        ParseAction::Push(ShellArg::Parameter(ParamExpansion::Splat(
+                            String::from("@"),
+                        ))),
+                        true,
+                    ))
                 } else if discriminator.is_alphanumeric() || discriminator == '_' {
                     if !new_char.is_alphanumeric() && new_char != '_' {
                         let mut env_name = discriminator.to_string();
@@ -348,6 +1647,23 @@ impl ParseCtxType {
                     Err(ParseError::InvalidEscapeSequence)
                 }
             }
+            ';' => match new_char {
+                ';' => Ok((ParseAction::Operator(Operator::CaseBreak), false)),
+                _ => Ok((ParseAction::Operator(Operator::Sequence), true)),
+            },
+            '|' => match new_char {
+                '|' => Ok((ParseAction::Operator(Operator::Or), false)),
+                _ => Ok((ParseAction::Operator(Operator::Pipe), true)),
+            },
+            '>' => match new_char {
+                '>' => Ok((ParseAction::Operator(Operator::RedirectAppend), false)),
+                _ => Ok((ParseAction::Operator(Operator::RedirectOutput), true)),
+            },
+            // no background jobs, a lone '&' is just a char
+            '&' => match new_char {
+                '&' => Ok((ParseAction::Operator(Operator::And), false)),
+                _ => Ok((ParseAction::Push(ShellArg::Char('&')), true)),
+            },
             _ => Err(ParseError::InvalidEscapeSequence),
         }
     }
@@ -361,22 +1677,53 @@ impl ParseCtx {
         }
     }
 
-    pub fn from_chars(iter: &mut impl Iterator<Item = char>) -> Result<ShellArgs, ParseError> {
+    /// The next chars pushed are from `column` onwards of `line`
+    pub fn start_line(&mut self, line: usize, column: usize) {
+        self.line = line;
+        self.column = column;
+    }
+
+    pub fn from_chars(
+        iter: &mut impl Iterator<Item = char>,
+    ) -> Result<CommandList, Spanned<ParseError>> {
         let mut new_ctx = Self::default();
         if new_ctx.push_chars(&mut iter.peekable())?.is_some() {
-            do yeet ParseError::IllegalRootUnnest;
+            do yeet Spanned::new(ParseError::IllegalRootUnnest, new_ctx.here());
+        }
+        CommandList::from_tokens(new_ctx.forced_close(new_ctx.here())?, None)
+    }
+
+    /// Parses a single word, as in [`ParseCtxType::Word`]
+    pub fn word_from_chars(
+        iter: &mut impl Iterator<Item = char>,
+    ) -> Result<Vec<ShellArg>, Spanned<ParseError>> {
+        let mut new_ctx = Self::new(ParseCtxType::Word);
+        if new_ctx.push_chars(&mut iter.peekable())?.is_some() {
+            do yeet Spanned::new(ParseError::IllegalRootUnnest, new_ctx.here());
         }
-        new_ctx.forced_close()
+        Ok(new_ctx
+            .forced_close(new_ctx.here())?
+            .into_iter()
+            .flat_map(|(token, _)| match token {
+                Token::Word(arg) => arg,
+                Token::Operator(_) => vec![],
+            })
+            .collect())
+    }
+
+    /// Empty span at the next char
+    fn here(&self) -> Span {
+        Span::new(self.line, self.column, 0)
     }
 
     pub fn push_chars(
         &mut self,
         iter: &mut Peekable<impl Iterator<Item = char>>,
-    ) -> Result<Option<ShellArgs>, ParseError> {
+    ) -> Result<Option<Vec<(Token, Span)>>, Spanned<ParseError>> {
         while let Some(ch) = iter.next() {
             if let Some(args) = self.push_char(ch)? {
                 if iter.peek().is_some() {
-                    do yeet ParseError::IllegalRootUnnest;
+                    do yeet Spanned::new(ParseError::IllegalRootUnnest, self.here());
                 }
                 return Ok(Some(args));
             };
@@ -384,80 +1731,200 @@ impl ParseCtx {
         Ok(None)
     }
 
-    pub fn push_char(&mut self, ch: char) -> Result<Option<ShellArgs>, ParseError> {
+    pub fn push_char(
+        &mut self,
+        ch: char,
+    ) -> Result<Option<Vec<(Token, Span)>>, Spanned<ParseError>> {
+        let at = Span::new(self.line, self.column, 1);
+        self.column += 1;
+        self.push(ch, at)
+    }
+
+    /// `at` is where `ch` is in the message, nested contexts get it from
+    /// the root one
+    fn push(
+        &mut self,
+        ch: char,
+        at: Span,
+    ) -> Result<Option<Vec<(Token, Span)>>, Spanned<ParseError>> {
         if let Some(ref mut nesting) = *(self.nesting) {
-            if let Some(args) = nesting.push_char(ch)? {
+            if let Some(tokens) = nesting.push(ch, at)? {
                 if nesting.typ == ParseCtxType::Normal(false) {
-                    self.arg.push(ShellArg::Subshell(args));
+                    self.arg.push(ShellArg::Subshell(CommandList::from_tokens(
+                        tokens,
+                        self.max_depth,
+                    )?));
                 } else {
-                    let mut flatten = args.0.into_iter().flatten().collect();
-                    self.arg.append(&mut flatten);
+                    tokens.into_iter().for_each(|(token, _)| {
+                        if let Token::Word(mut arg) = token {
+                            self.arg.append(&mut arg);
+                        }
+                    });
+                    self.quoted = true;
                 }
                 *self.nesting = None;
             };
             return Ok(None);
         };
 
-        let (act, requeue) = if self.escape.is_empty() {
-            (self.typ.token(ch)?, false)
-        } else {
-            let (act, requeue) = self.typ.escape(&self.escape, ch)?;
+        let escaped = !self.escape.is_empty();
+        // only unquoted and unescaped braces expand
+        let literal = !escaped && matches!(self.typ, ParseCtxType::Normal(_));
+        let (act, requeue) = if escaped {
+            let (act, requeue) = self
+                .typ
+                .escape(&self.escape, ch, self.max_depth)
+                .map_err(|err| Spanned::new(err, self.escape_start.to(at)))?;
             if act != ParseAction::EscapeSequence {
                 self.escape = String::new();
             };
             (act, requeue)
+        } else {
+            (
+                self.typ.token(ch).map_err(|err| Spanned::new(err, at))?,
+                false,
+            )
         };
+        // where whatever `ch` completed starts
+        let start = if escaped { self.escape_start } else { at };
 
-        let just_separated_binding = act == ParseAction::Separator;
         match act {
-            ParseAction::Nest(ctx_typ) => *self.nesting = Some(Self::new(ctx_typ)),
+            ParseAction::Nest(ctx_typ) => {
+                if let Some(max_depth) = self.max_depth.filter(|max_depth| self.depth >= *max_depth)
+                {
+                    do yeet Spanned::new(ParseError::NestingTooDeep(max_depth), start.to(at));
+                }
+                self.word_start.get_or_insert(start);
+                self.nesting_start = start;
+                *self.nesting = Some(Self {
+                    depth: self.depth + 1,
+                    max_depth: self.max_depth,
+                    ..Self::new(ctx_typ)
+                });
+            }
             ParseAction::Unnest => match *self.nesting {
-                Some(_) => do yeet ParseError::IllegalRootUnnest,
+                Some(_) => do yeet Spanned::new(ParseError::IllegalRootUnnest, at),
                 None => {
-                    return Ok(Some(self.forced_close()?));
+                    return Ok(Some(self.forced_close(Span { len: 0, ..at })?));
                 }
             },
-            ParseAction::EscapeSequence => self.escape.push(ch),
-            ParseAction::Push(token) => self.arg.push(token),
-            ParseAction::Separator => {
-                if !self.just_separated {
-                    self.args.0.push(take(&mut self.arg));
+            ParseAction::EscapeSequence => {
+                if !escaped {
+                    self.escape_start = at;
                 }
+                self.escape.push(ch)
+            }
+            ParseAction::Push(token) => {
+                if literal && matches!(token, ShellArg::Char('{' | ',' | '}')) {
+                    self.braces.push(self.arg.len());
+                }
+                self.word_start.get_or_insert(start);
+                self.arg.push(token)
+            }
+            ParseAction::Separator => self.push_arg(Span { len: 0, ..at })?,
+            ParseAction::Operator(op) => {
+                self.push_arg(Span { len: 0, ..start })?;
+                // a requeued char isn't part of the operator
+                let end = if requeue { Span { len: 0, ..at } } else { at };
+                self.tokens.push((Token::Operator(op), start.to(end)));
             }
         };
-        self.just_separated = just_separated_binding;
 
         if requeue {
-            self.push_char(ch)
+            self.push(ch, at)
         } else {
             Ok(None)
         }
     }
 
-    pub fn forced_close(&mut self) -> Result<ShellArgs, ParseError> {
-        self.close()?.ok_or(ParseError::InvalidEscapeSequence)
+    /// `end` is right after the word
+    fn push_arg(&mut self, end: Span) -> Result<(), Spanned<ParseError>> {
+        let span = self.word_start.take().unwrap_or(end).to(end);
+        let braces = take(&mut self.braces);
+        if braces.is_empty() {
+            if !self.arg.is_empty() || self.quoted {
+                self.tokens.push((Token::Word(take(&mut self.arg)), span));
+            }
+        } else {
+            // like bash, words left empty by the expansion are dropped
+            let words = brace::expand(take(&mut self.arg), &braces)
+                .map_err(|err| Spanned::new(err, span))?;
+            for word in words {
+                if !word.is_empty() || self.quoted {
+                    self.tokens.push((Token::Word(word), span));
+                }
+            }
+        }
+        self.quoted = false;
+        Ok(())
+    }
+
+    pub fn forced_close(&mut self, end: Span) -> Result<Vec<(Token, Span)>, Spanned<ParseError>> {
+        let escape_span = self.escape_start.to(end);
+        self.close_at(end)?
+            .ok_or(Spanned::new(ParseError::InvalidEscapeSequence, escape_span))
+    }
+
+    pub fn close(&mut self) -> Result<Option<Vec<(Token, Span)>>, Spanned<ParseError>> {
+        self.close_at(self.here())
     }
 
-    pub fn close(&mut self) -> Result<Option<ShellArgs>, ParseError> {
+    /// `end` is right after the last char
+    fn close_at(&mut self, end: Span) -> Result<Option<Vec<(Token, Span)>>, Spanned<ParseError>> {
         if self.nesting.is_some() {
-            do yeet ParseError::IllegalRootUnnest;
+            do yeet Spanned::new(ParseError::IllegalRootUnnest, self.nesting_start.to(end));
         }
 
+        let escape_span = self.escape_start.to(end);
         match self.escape.as_ref() {
             "\\" => Ok(None),
             "" => {
-                self.args.0.push(take(&mut self.arg));
-                Ok(Some(take(&mut self.args)))
+                self.push_arg(end)?;
+                // a trailing `|`, `&&` or `||` continues the command on the next line
+                if let Some((Token::Operator(Operator::Pipe | Operator::And | Operator::Or), _)) =
+                    self.tokens.last()
+                {
+                    return Ok(None);
+                }
+                Ok(Some(take(&mut self.tokens)))
             }
             _ => {
+                if [";", "|", "&", ">"].contains(&self.escape.as_str()) {
+                    let (act, _) = self
+                        .typ
+                        .escape(&take(&mut self.escape), '\n', self.max_depth)
+                        .map_err(|err| Spanned::new(err, escape_span))?;
+                    match act {
+                        ParseAction::Operator(op) => {
+                            self.push_arg(Span {
+                                len: 0,
+                                ..self.escape_start
+                            })?;
+                            self.tokens.push((Token::Operator(op), self.escape_start));
+                        }
+                        ParseAction::Push(token) => {
+                            self.word_start.get_or_insert(self.escape_start);
+                            self.arg.push(token)
+                        }
+                        _ => do yeet Spanned::new(ParseError::InvalidEscapeSequence, escape_span),
+                    };
+                    return self.close_at(end);
+                }
+                if self.escape.starts_with("$((") {
+                    do yeet Spanned::new(ParseError::InvalidArithmetic, escape_span);
+                }
                 //  This allows to type env variables starting with '{' as long
                 // as they're at closure, not expected behavior but is harmless
                 if self.escape.starts_with('$') {
-                    self.arg.push(ShellArg::EnvVar(self.escape[1..].to_owned()));
+                    self.word_start.get_or_insert(self.escape_start);
+                    self.arg.push(match &self.escape[1..] {
+                        "@" => ShellArg::Parameter(ParamExpansion::Splat(String::from("@"))),
+                        name => ShellArg::EnvVar(name.to_owned()),
+                    });
                     self.escape = String::new();
-                    return self.close();
+                    return self.close_at(end);
                 }
-                Err(ParseError::InvalidEscapeSequence)
+                Err(Spanned::new(ParseError::InvalidEscapeSequence, escape_span))
             }
         }
     }
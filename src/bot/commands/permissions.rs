@@ -0,0 +1,433 @@
+//! Who can run which command. Commands require permissions, guilds allow or
+//! deny them to roles and channels with `perm`, stored in SurrealDB per guild
+
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+use serenity::{
+    model::{
+        channel::Message,
+        id::{ChannelId, GuildId, RoleId, UserId},
+        Permissions,
+    },
+    prelude::*,
+};
+
+const GUILD_TABLE: &str = "guild_permissions";
+
+/// What a command needs to run, the bot's owners can always run everything
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Requirement {
+    pub owner_only: bool,
+    /// Needed in the channel, commands needing any only run in guilds
+    pub permissions: Permissions,
+}
+
+impl Requirement {
+    pub const OWNER_ONLY: Self = Self {
+        owner_only: true,
+        permissions: Permissions::empty(),
+    };
+
+    pub const fn permissions(permissions: Permissions) -> Self {
+        Self {
+            owner_only: false,
+            permissions,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Target {
+    Role(RoleId),
+    Channel(ChannelId),
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Role(id) => write!(f, "role {id}"),
+            Self::Channel(id) => write!(f, "channel {id}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    pub command: String,
+    pub target: Target,
+    pub allow: bool,
+}
+
+/// A guild's rules. Denying wins over allowing, and an allowed command
+/// doesn't need its [`Requirement::permissions`].
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rules {
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub async fn load(
+        db: &surrealdb::Surreal<impl surrealdb::Connection>,
+        guild_id: GuildId,
+    ) -> surrealdb::Result<Self> {
+        let rules: Option<Self> = db.select((GUILD_TABLE, guild_id.get().to_string())).await?;
+        Ok(rules.unwrap_or_default())
+    }
+
+    pub async fn store(
+        &self,
+        db: &surrealdb::Surreal<impl surrealdb::Connection>,
+        guild_id: GuildId,
+    ) -> surrealdb::Result<()> {
+        let _: Option<Self> = db
+            .update((GUILD_TABLE, guild_id.get().to_string()))
+            .content(self)
+            .await?;
+        Ok(())
+    }
+
+    /// Replaces the rule for the same command and target
+    pub fn set(&mut self, rule: Rule) {
+        self.remove(&rule.command, Some(rule.target));
+        self.rules.push(rule);
+    }
+
+    /// Removes `command`'s rules, only the one for `target` if there's one.
+    /// Returns how many were removed.
+    pub fn remove(&mut self, command: &str, target: Option<Target>) -> usize {
+        let len = self.rules.len();
+        self.rules.retain(|rule| {
+            rule.command != command || target.is_some_and(|target| rule.target != target)
+        });
+        len - self.rules.len()
+    }
+}
+
+#[derive(Debug)]
+pub enum Denied {
+    OwnerOnly(String),
+    GuildOnly(String),
+    Rule(String),
+    /// The guild's rules couldn't be loaded, any of them could deny it
+    RulesUnavailable(String),
+    MissingPermissions {
+        command: String,
+        missing: Permissions,
+    },
+}
+
+impl Display for Denied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OwnerOnly(command) => {
+                write!(f, "`{command}` can only be ran by the bot's owners")
+            }
+            Self::GuildOnly(command) => write!(f, "`{command}` only runs in servers"),
+            Self::Rule(command) => write!(f, "`{command}` is denied here"),
+            Self::RulesUnavailable(command) => write!(
+                f,
+                "`{command}` can't run, this server's rules couldn't be loaded"
+            ),
+            Self::MissingPermissions { command, missing } => {
+                let names = missing.get_permission_names();
+                let plural = if names.len() == 1 { "" } else { "s" };
+                write!(
+                    f,
+                    "`{command}` needs the {} permission{plural}",
+                    names.join(", ")
+                )
+            }
+        }
+    }
+}
+
+impl super::CommandError for Denied {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::OwnerOnly(_) => "E006",
+            Self::GuildOnly(_) => "E007",
+            Self::Rule(_) => "E008",
+            Self::MissingPermissions { .. } => "E009",
+            Self::RulesUnavailable(_) => "E011",
+        }
+    }
+
+    fn hint(&self) -> Option<String> {
+        match self {
+            Self::Rule(_) | Self::MissingPermissions { .. } => Some(String::from(
+                "the server's admins can allow it with `perm allow`",
+            )),
+            Self::RulesUnavailable(_) => Some(String::from("try again later")),
+            _ => None,
+        }
+    }
+}
+
+/// The author's roles and permissions in the message's channel
+#[derive(Debug, Clone)]
+struct GuildAccess {
+    channel_id: ChannelId,
+    /// `@everyone` included
+    roles: Vec<RoleId>,
+    permissions: Permissions,
+    /// `None` if they couldn't be loaded, only admins can run commands then
+    rules: Option<Rules>,
+}
+
+/// Where a message's author stands, checked before each of its commands
+#[derive(Debug, Clone)]
+pub struct Access {
+    owner: bool,
+    /// `None` in DMs
+    guild: Option<GuildAccess>,
+}
+
+impl Access {
+    /// Rules that fail to load are logged and every command is denied to
+    /// non-admins, roles and permissions come from the cache
+    pub async fn load(
+        ctx: &Context,
+        msg: &Message,
+        db: &surrealdb::Surreal<impl surrealdb::Connection>,
+        owners: &[UserId],
+    ) -> Self {
+        let owner = owners.contains(&msg.author.id);
+        let Some(guild_id) = msg.guild_id else {
+            return Self { owner, guild: None };
+        };
+
+        let rules = Rules::load(db, guild_id)
+            .await
+            .inspect_err(|err| println!("Error loading permission rules {err:?}"))
+            .ok();
+        let mut roles = msg
+            .member
+            .as_ref()
+            .map(|member| member.roles.clone())
+            .unwrap_or_default();
+        // `@everyone` has the guild's id
+        roles.push(RoleId::new(guild_id.get()));
+
+        Self {
+            owner,
+            guild: Some(GuildAccess {
+                channel_id: msg.channel_id,
+                roles,
+                permissions: permissions(ctx, msg, guild_id),
+                rules,
+            }),
+        }
+    }
+
+    pub fn check(&self, command: &str, requirement: Requirement) -> Result<(), Denied> {
+        if self.owner {
+            return Ok(());
+        }
+        if requirement.owner_only {
+            do yeet Denied::OwnerOnly(command.to_owned());
+        }
+
+        let Some(guild) = &self.guild else {
+            if !requirement.permissions.is_empty() {
+                do yeet Denied::GuildOnly(command.to_owned());
+            }
+            return Ok(());
+        };
+        if guild.permissions.administrator() {
+            return Ok(());
+        }
+
+        let Some(rules) = &guild.rules else {
+            do yeet Denied::RulesUnavailable(command.to_owned());
+        };
+
+        let mut allowed = false;
+        for rule in rules.rules.iter().filter(|rule| rule.command == command) {
+            let applies = match rule.target {
+                Target::Role(role) => guild.roles.contains(&role),
+                Target::Channel(channel) => channel == guild.channel_id,
+            };
+            match (applies, rule.allow) {
+                (true, false) => do yeet Denied::Rule(command.to_owned()),
+                (true, true) => allowed = true,
+                (false, _) => {}
+            }
+        }
+
+        let missing = requirement.permissions - guild.permissions;
+        if !allowed && !missing.is_empty() {
+            do yeet Denied::MissingPermissions {
+                command: command.to_owned(),
+                missing,
+            };
+        }
+        Ok(())
+    }
+}
+
+/// In the channel, or in the parent channel for threads. Nothing if they
+/// aren't cached.
+fn permissions(ctx: &Context, msg: &Message, guild_id: GuildId) -> Permissions {
+    let (Some(guild), Some(member)) = (ctx.cache.guild(guild_id), &msg.member) else {
+        return Permissions::empty();
+    };
+
+    let channel = guild.channels.get(&msg.channel_id).or_else(|| {
+        let thread = guild
+            .threads
+            .iter()
+            .find(|thread| thread.id == msg.channel_id)?;
+        guild.channels.get(&thread.parent_id?)
+    });
+    match channel {
+        Some(channel) => guild.partial_member_permissions_in(channel, msg.author.id, member),
+        None => Permissions::empty(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::CommandError, *};
+
+    const CHANNEL: ChannelId = ChannelId::new(1);
+    const ROLE: RoleId = RoleId::new(2);
+    const OTHER_ROLE: RoleId = RoleId::new(3);
+    const NEEDS_MANAGE: Requirement = Requirement::permissions(Permissions::MANAGE_GUILD);
+
+    fn rule(command: &str, target: Target, allow: bool) -> Rule {
+        Rule {
+            command: command.to_owned(),
+            target,
+            allow,
+        }
+    }
+
+    fn member(permissions: Permissions, rules: Option<Vec<Rule>>) -> Access {
+        Access {
+            owner: false,
+            guild: Some(GuildAccess {
+                channel_id: CHANNEL,
+                roles: vec![ROLE],
+                permissions,
+                rules: rules.map(|rules| Rules { rules }),
+            }),
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        let deny_all = || {
+            Some(vec![
+                rule("cmd", Target::Role(ROLE), false),
+                rule("cmd", Target::Channel(CHANNEL), false),
+            ])
+        };
+        let owner = Access {
+            owner: true,
+            ..member(Permissions::empty(), deny_all())
+        };
+        let admin = member(Permissions::ADMINISTRATOR, None);
+        let dm = Access {
+            owner: false,
+            guild: None,
+        };
+
+        let cases = [
+            ("owner bypasses rules", &owner, NEEDS_MANAGE, Ok(())),
+            (
+                "owner runs owner only",
+                &owner,
+                Requirement::OWNER_ONLY,
+                Ok(()),
+            ),
+            (
+                "admin isn't owner",
+                &admin,
+                Requirement::OWNER_ONLY,
+                Err("E006"),
+            ),
+            ("DMs have no permissions", &dm, NEEDS_MANAGE, Err("E007")),
+            ("DMs need nothing else", &dm, Requirement::default(), Ok(())),
+            ("admin needs no rules", &admin, NEEDS_MANAGE, Ok(())),
+            (
+                "admin bypasses deny",
+                &member(Permissions::ADMINISTRATOR, deny_all()),
+                Requirement::default(),
+                Ok(()),
+            ),
+            (
+                "missing rules deny",
+                &member(Permissions::all() - Permissions::ADMINISTRATOR, None),
+                Requirement::default(),
+                Err("E011"),
+            ),
+            (
+                "deny wins over allow",
+                &member(
+                    Permissions::empty(),
+                    Some(vec![
+                        rule("cmd", Target::Role(ROLE), true),
+                        rule("cmd", Target::Channel(CHANNEL), false),
+                    ]),
+                ),
+                Requirement::default(),
+                Err("E008"),
+            ),
+            (
+                "allow bypasses permissions",
+                &member(
+                    Permissions::empty(),
+                    Some(vec![rule("cmd", Target::Channel(CHANNEL), true)]),
+                ),
+                NEEDS_MANAGE,
+                Ok(()),
+            ),
+            (
+                "missing permissions",
+                &member(Permissions::empty(), Some(vec![])),
+                NEEDS_MANAGE,
+                Err("E009"),
+            ),
+            (
+                "having the permissions",
+                &member(Permissions::MANAGE_GUILD, Some(vec![])),
+                NEEDS_MANAGE,
+                Ok(()),
+            ),
+            (
+                "other roles' and commands' rules don't apply",
+                &member(
+                    Permissions::empty(),
+                    Some(vec![
+                        rule("cmd", Target::Role(OTHER_ROLE), false),
+                        rule("other", Target::Role(ROLE), false),
+                        rule("cmd", Target::Role(OTHER_ROLE), true),
+                        rule("other", Target::Role(ROLE), true),
+                    ]),
+                ),
+                NEEDS_MANAGE,
+                Err("E009"),
+            ),
+        ];
+
+        for (name, access, requirement, expected) in cases {
+            let checked = access.check("cmd", requirement).map_err(|err| err.code());
+            assert_eq!(checked, expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn rules_replace_the_same_target() {
+        let mut rules = Rules::default();
+        rules.set(rule("cmd", Target::Role(ROLE), true));
+        rules.set(rule("cmd", Target::Role(ROLE), false));
+        rules.set(rule("cmd", Target::Channel(CHANNEL), true));
+        assert_eq!(rules.rules.len(), 2);
+        assert!(!rules.rules[0].allow);
+
+        assert_eq!(rules.remove("cmd", Some(Target::Role(OTHER_ROLE))), 0);
+        assert_eq!(rules.remove("cmd", None), 2);
+    }
+}
@@ -0,0 +1,627 @@
+//! Canonical source for parsed scripts, printing a [`CommandList`] and
+//! parsing it back gives the same tree
+//!
+//! Everything goes on one line so it also works inside `$(...)`. Chars with
+//! a meaning are escaped instead of quoted, quotes only ever mark an empty
+//! word.
+
+use super::parser::{
+    is_keyword, Command, CommandList, Operator, ParamExpansion, Pipeline, Redirect, ShellArg,
+    ShellArgs,
+};
+
+use std::fmt::{Display, Formatter, Result};
+
+/// Chars the tokenizer takes as their own escape, `\c`
+const ESCAPED_CHARS: &[char] = &['\\', ' ', '(', ')', '{', '}', '|', '&', ';', '<', '>'];
+/// Chars with an escape letter, `\n`
+const ESCAPED_BYTES: &[(char, char)] = &[('\n', 'n'), ('\r', 'r'), ('\t', 't'), ('\0', '0')];
+
+/// `param` is set inside `${...}`, where `/` splits replacements
+fn write_char(f: &mut Formatter<'_>, ch: char, param: bool) -> Result {
+    if ESCAPED_CHARS.contains(&ch) {
+        return write!(f, "\\{ch}");
+    }
+    if let Some((_, letter)) = ESCAPED_BYTES.iter().find(|(byte, _)| *byte == ch) {
+        return write!(f, "\\{letter}");
+    }
+
+    match ch {
+        // these have no escape of their own
+        '\'' | '"' | '$' => write!(f, "\\u{{{:x}}}", ch as u32),
+        '/' if param => write!(f, "\\u{{{:x}}}", ch as u32),
+        ch if ch.is_control() => write!(f, "\\u{{{:x}}}", ch as u32),
+        ch => write!(f, "{ch}"),
+    }
+}
+
+fn write_arg(f: &mut Formatter<'_>, arg: &ShellArg, param: bool) -> Result {
+    match arg {
+        ShellArg::Byte(byte) => write!(f, "\\x{byte:02x}"),
+        ShellArg::Char(ch) => write_char(f, *ch, param),
+        ShellArg::RawString(string) => string
+            .to_string_lossy()
+            .chars()
+            .try_for_each(|ch| write_char(f, ch, param)),
+        ShellArg::String(string) => string.chars().try_for_each(|ch| write_char(f, ch, param)),
+        ShellArg::EnvVar(name) if ParamExpansion::is_name(name) => write!(f, "${{{name}}}"),
+        // an unclosed `${` at the end of the script, it's only valid there
+        ShellArg::EnvVar(name) => write!(f, "${name}"),
+        ShellArg::Parameter(param) => write!(f, "{param}"),
+        ShellArg::Arithmetic(expr) => write!(f, "$(({expr}))"),
+        ShellArg::Subshell(list) => write!(f, "$({list})"),
+    }
+}
+
+/// An empty word is `""`, so it isn't dropped
+fn write_word(f: &mut Formatter<'_>, word: &[ShellArg], param: bool) -> Result {
+    if word.is_empty() {
+        return write!(f, "\"\"");
+    }
+    word.iter().try_for_each(|arg| write_arg(f, arg, param))
+}
+
+fn write_words(f: &mut Formatter<'_>, words: &[Vec<ShellArg>], separator: &str) -> Result {
+    for (i, word) in words.iter().enumerate() {
+        if i > 0 {
+            write!(f, "{separator}")?;
+        }
+        write_word(f, word, false)?;
+    }
+    Ok(())
+}
+
+impl Display for ShellArg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write_arg(f, self, false)
+    }
+}
+
+impl Display for ParamExpansion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Self::Default { name, word, assign } => {
+                write!(f, "${{{name}:{}", if *assign { '=' } else { '-' })?;
+                write_word(f, word, true)?;
+            }
+            Self::Length(name) => write!(f, "${{#{name}")?,
+            Self::Substring {
+                name,
+                offset,
+                length,
+            } => {
+                // `${NAME:-1}` would be a default value
+                write!(f, "${{{name}: {offset}")?;
+                if let Some(length) = length {
+                    write!(f, ":{length}")?;
+                }
+            }
+            Self::Replace {
+                name,
+                pattern,
+                replacement,
+                all,
+            } => {
+                write!(f, "${{{name}/{}", if *all { "/" } else { "" })?;
+                write_word(f, pattern, true)?;
+                write!(f, "/")?;
+                write_word(f, replacement, true)?;
+            }
+            Self::Index { name, index } => {
+                write!(f, "${{{name}[")?;
+                // `${NAME[@]}` would be a splat
+                if ShellArg::literal(index).as_deref() == Some("@") {
+                    write!(f, "\\u{{40}}")?;
+                } else {
+                    write_word(f, index, true)?;
+                }
+                write!(f, "]")?;
+            }
+            Self::Splat(name) if name == "@" => write!(f, "${{@")?,
+            Self::Splat(name) => write!(f, "${{{name}[@]")?,
+        }
+        write!(f, "}}")
+    }
+}
+
+impl Display for Redirect {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let (op, target) = match self {
+            Self::Input(target) => (Operator::RedirectInput, target),
+            Self::Output(target) => (Operator::RedirectOutput, target),
+            Self::Append(target) => (Operator::RedirectAppend, target),
+        };
+        write!(f, "{op} ")?;
+        write_word(f, target, false)
+    }
+}
+
+/// Redirections go after the words, wherever they were, unless the first
+/// word would be a keyword there or the last one has to end the script
+impl Display for ShellArgs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let keyword = self
+            .args
+            .first()
+            .and_then(|word| ShellArg::literal(word))
+            .is_some_and(|word| is_keyword(&word));
+        let unclosed = self.args.last().and_then(|word| word.last()).is_some_and(
+            |arg| matches!(arg, ShellArg::EnvVar(name) if !ParamExpansion::is_name(name)),
+        );
+
+        if keyword || unclosed {
+            for redirect in &self.redirects {
+                write!(f, "{redirect} ")?;
+            }
+            return write_words(f, &self.args, " ");
+        }
+
+        write_words(f, &self.args, " ")?;
+        for (i, redirect) in self.redirects.iter().enumerate() {
+            if i > 0 || !self.args.is_empty() {
+                write!(f, " ")?;
+            }
+            write!(f, "{redirect}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Self::Simple(args) => write!(f, "{args}"),
+            Self::If {
+                branches,
+                otherwise,
+                ..
+            } => {
+                for (i, (cond, body)) in branches.iter().enumerate() {
+                    let keyword = if i == 0 { "if" } else { "elif" };
+                    write!(f, "{keyword} {cond}; then {body}; ")?;
+                }
+                if let Some(otherwise) = otherwise {
+                    write!(f, "else {otherwise}; ")?;
+                }
+                write!(f, "fi")
+            }
+            Self::For {
+                var, words, body, ..
+            } => {
+                write!(f, "for {var} in")?;
+                if !words.is_empty() {
+                    write!(f, " ")?;
+                    write_words(f, words, " ")?;
+                }
+                write!(f, "; do {body}; done")
+            }
+            Self::While {
+                cond, body, until, ..
+            } => {
+                let keyword = if *until { "until" } else { "while" };
+                write!(f, "{keyword} {cond}; do {body}; done")
+            }
+            Self::Case { word, items, .. } => {
+                write!(f, "case ")?;
+                write_word(f, word, false)?;
+                write!(f, " in ")?;
+                for (patterns, body) in items {
+                    write_words(f, patterns, " | ")?;
+                    write!(f, ") {body};; ")?;
+                }
+                write!(f, "esac")
+            }
+            Self::Function { name, body } => write!(f, "function {name} {{ {body}; }}"),
+        }
+    }
+}
+
+impl Display for Pipeline {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for (i, command) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " | ")?;
+            }
+            write!(f, "{command}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for CommandList {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for (i, (op, pipeline)) in self.0.iter().enumerate() {
+            if i > 0 {
+                match op {
+                    Operator::Sequence | Operator::Newline => write!(f, "; ")?,
+                    op => write!(f, " {op} ")?,
+                }
+            }
+            write!(f, "{pipeline}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        arithmetic::{BinaryOp, Expr, UnaryOp},
+        parser::*,
+    };
+
+    use proptest::{collection::vec, prelude::*, sample::select};
+
+    /// Spans aren't serialized, so this compares the trees without them
+    fn assert_same(parsed: &CommandList, expected: &CommandList) {
+        assert_eq!(
+            serde_json::to_string(parsed).unwrap(),
+            serde_json::to_string(expected).unwrap()
+        );
+    }
+
+    fn reparse(list: &CommandList) -> CommandList {
+        let source = list.to_string();
+        ParseCtx::from_chars(&mut source.chars())
+            .unwrap_or_else(|err| panic!("{source:?} doesn't parse: {err:?}"))
+    }
+
+    fn name() -> impl Strategy<Value = String> {
+        "[a-zA-Z_][a-zA-Z0-9_]{0,5}"
+    }
+
+    fn chr() -> impl Strategy<Value = char> {
+        prop_oneof![
+            any::<char>(),
+            select(vec![
+                '\\', ' ', '(', ')', '{', '}', ',', '|', '&', ';', '<', '>', '\'', '"', '$', '/',
+                '\n', '\r', '\t', '\0', '\u{7f}', 'x', 'u', '.', '1',
+            ]),
+        ]
+    }
+
+    fn expr() -> impl Strategy<Value = Expr> {
+        let leaf = prop_oneof![
+            (0..=i128::MAX).prop_map(Expr::Literal),
+            name().prop_map(Expr::Var),
+        ];
+        leaf.prop_recursive(3, 16, 2, |inner| {
+            prop_oneof![
+                (
+                    select(vec![
+                        UnaryOp::Neg,
+                        UnaryOp::Plus,
+                        UnaryOp::Not,
+                        UnaryOp::BitNot
+                    ]),
+                    inner.clone()
+                )
+                    .prop_map(|(op, expr)| Expr::Unary(op, Box::new(expr))),
+                (
+                    select(vec![
+                        BinaryOp::Pow,
+                        BinaryOp::Mul,
+                        BinaryOp::Div,
+                        BinaryOp::Rem,
+                        BinaryOp::Add,
+                        BinaryOp::Sub,
+                        BinaryOp::Shl,
+                        BinaryOp::Shr,
+                        BinaryOp::Lt,
+                        BinaryOp::Le,
+                        BinaryOp::Gt,
+                        BinaryOp::Ge,
+                        BinaryOp::Eq,
+                        BinaryOp::Ne,
+                        BinaryOp::BitAnd,
+                        BinaryOp::BitXor,
+                        BinaryOp::BitOr,
+                        BinaryOp::And,
+                        BinaryOp::Or,
+                    ]),
+                    inner.clone(),
+                    inner,
+                )
+                    .prop_map(|(op, lhs, rhs)| Expr::Binary(
+                        op,
+                        Box::new(lhs),
+                        Box::new(rhs)
+                    )),
+            ]
+        })
+    }
+
+    /// Only chars, bytes and variables, `/` inside other expansions would
+    /// split a replacement's pattern
+    fn flat_word() -> impl Strategy<Value = Vec<ShellArg>> {
+        vec(
+            prop_oneof![
+                chr().prop_map(ShellArg::Char),
+                any::<u8>().prop_map(ShellArg::Byte),
+                name().prop_map(ShellArg::EnvVar),
+            ],
+            0..4,
+        )
+    }
+
+    fn param(depth: u32) -> BoxedStrategy<ParamExpansion> {
+        prop_oneof![
+            (name(), word(depth), any::<bool>()).prop_map(|(name, word, assign)| {
+                ParamExpansion::Default { name, word, assign }
+            }),
+            prop_oneof![name(), Just(String::from("?"))].prop_map(ParamExpansion::Length),
+            (name(), any::<i64>(), any::<Option<i64>>()).prop_map(|(name, offset, length)| {
+                ParamExpansion::Substring {
+                    name,
+                    offset,
+                    length,
+                }
+            }),
+            (name(), flat_word(), word(depth), any::<bool>()).prop_map(
+                |(name, pattern, replacement, all)| ParamExpansion::Replace {
+                    name,
+                    pattern,
+                    replacement,
+                    all,
+                }
+            ),
+            (name(), word(depth)).prop_map(|(name, index)| ParamExpansion::Index { name, index }),
+            prop_oneof![name(), Just(String::from("@"))].prop_map(ParamExpansion::Splat),
+        ]
+        .boxed()
+    }
+
+    fn arg(depth: u32) -> BoxedStrategy<ShellArg> {
+        let flat = prop_oneof![
+            4 => chr().prop_map(ShellArg::Char),
+            1 => any::<u8>().prop_map(ShellArg::Byte),
+            1 => prop_oneof![name(), Just(String::from("?")), "[0-9]"].prop_map(ShellArg::EnvVar),
+            1 => expr().prop_map(ShellArg::Arithmetic),
+        ];
+        if depth == 0 {
+            return flat.boxed();
+        }
+        prop_oneof![
+            6 => flat,
+            1 => param(depth - 1).prop_map(ShellArg::Parameter),
+            1 => list(depth - 1, true).prop_map(ShellArg::Subshell),
+        ]
+        .boxed()
+    }
+
+    fn word(depth: u32) -> BoxedStrategy<Vec<ShellArg>> {
+        vec(arg(depth), 0..4).boxed()
+    }
+
+    fn redirect(depth: u32) -> BoxedStrategy<Redirect> {
+        prop_oneof![
+            word(depth).prop_map(Redirect::Input),
+            word(depth).prop_map(Redirect::Output),
+            word(depth).prop_map(Redirect::Append),
+        ]
+        .boxed()
+    }
+
+    fn simple(depth: u32) -> BoxedStrategy<Command> {
+        (vec(word(depth), 0..4), vec(redirect(depth), 0..2))
+            .prop_filter("not a command", |(args, redirects)| {
+                let keyword = args
+                    .first()
+                    .and_then(|word| ShellArg::literal(word))
+                    .is_some_and(|word| is_keyword(&word));
+                // a keyword only works as a command after a redirection
+                (!args.is_empty() || !redirects.is_empty()) && (!keyword || !redirects.is_empty())
+            })
+            .prop_map(|(args, redirects)| {
+                Command::Simple(ShellArgs {
+                    args,
+                    redirects,
+                    ..Default::default()
+                })
+            })
+            .boxed()
+    }
+
+    /// `case` patterns end with a `)`, which would close a subshell
+    fn command(depth: u32, in_subshell: bool) -> BoxedStrategy<Command> {
+        if depth == 0 {
+            return simple(0);
+        }
+        let body = || list(depth - 1, in_subshell);
+
+        let mut commands = vec![
+            (8, simple(depth)),
+            (
+                1,
+                (vec((body(), body()), 1..3), proptest::option::of(body()))
+                    .prop_map(|(branches, otherwise)| Command::If {
+                        branches,
+                        otherwise,
+                        span: Default::default(),
+                    })
+                    .boxed(),
+            ),
+            (
+                1,
+                (name(), vec(word(depth - 1), 0..3), body())
+                    .prop_map(|(var, words, body)| Command::For {
+                        var,
+                        words,
+                        body,
+                        span: Default::default(),
+                    })
+                    .boxed(),
+            ),
+            (
+                1,
+                (body(), body(), any::<bool>())
+                    .prop_map(|(cond, body, until)| Command::While {
+                        cond,
+                        body,
+                        until,
+                        span: Default::default(),
+                    })
+                    .boxed(),
+            ),
+            (
+                1,
+                ("[a-z_][a-z0-9_-]{0,5}", body())
+                    .prop_map(|(name, body)| Command::Function { name, body })
+                    .boxed(),
+            ),
+        ];
+        if !in_subshell {
+            let item = (
+                vec(word(depth - 1), 1..3),
+                prop_oneof![Just(CommandList::default()), body()],
+            )
+                .prop_filter("esac", |(patterns, _)| {
+                    ShellArg::literal(&patterns[0]).as_deref() != Some("esac")
+                });
+            commands.push((
+                1,
+                (word(depth - 1), vec(item, 0..3))
+                    .prop_map(|(word, items)| Command::Case {
+                        word,
+                        items,
+                        span: Default::default(),
+                    })
+                    .boxed(),
+            ));
+        }
+
+        proptest::strategy::Union::new_weighted(commands).boxed()
+    }
+
+    fn list(depth: u32, in_subshell: bool) -> BoxedStrategy<CommandList> {
+        let pipeline = vec(command(depth, in_subshell), 1..3).prop_map(Pipeline);
+        let op = select(vec![Operator::Sequence, Operator::And, Operator::Or]);
+
+        (pipeline.clone(), vec((op, pipeline), 0..3))
+            .prop_map(|(first, rest)| {
+                CommandList(
+                    std::iter::once((Operator::Sequence, first))
+                        .chain(rest)
+                        .collect(),
+                )
+            })
+            .boxed()
+    }
+
+    /// Source made of pieces that are likely to form valid scripts
+    fn source() -> impl Strategy<Value = String> {
+        let piece = select(vec![
+            "a",
+            "b",
+            "1",
+            " ",
+            " ",
+            "$a",
+            "${a}",
+            "$?",
+            "${a:-b c}",
+            "${#a}",
+            "${a:1:-1}",
+            "${a//x/y}",
+            "$(",
+            ")",
+            "$((1 + a * 2))",
+            "'",
+            "\"",
+            "\\",
+            "\\x41",
+            "\\u{e9}",
+            "\\n",
+            "\\ ",
+            "{",
+            "}",
+            ",",
+            "..",
+            "|",
+            "&&",
+            "||",
+            ";",
+            ";;",
+            "<",
+            ">",
+            ">>",
+            "if",
+            "then",
+            "elif",
+            "else",
+            "fi",
+            "for",
+            "in",
+            "do",
+            "done",
+            "while",
+            "case",
+            "esac",
+            "function",
+        ]);
+        vec(piece, 0..16).prop_map(|pieces| pieces.concat())
+    }
+
+    proptest! {
+        #[test]
+        fn printed_lists_parse_back(list in list(2, false)) {
+            assert_same(&reparse(&list), &list);
+        }
+
+        #[test]
+        fn printed_source_parses_to_the_same_tree(source in source()) {
+            if let Ok(list) = ParseCtx::from_chars(&mut source.chars()) {
+                assert_same(&reparse(&list), &list);
+            }
+        }
+
+        #[test]
+        fn printing_is_stable(list in list(2, false)) {
+            let printed = list.to_string();
+            prop_assert_eq!(reparse(&list).to_string(), printed);
+        }
+    }
+
+    #[test]
+    fn prints_canonical_source() {
+        let cases = [
+            ("echo 'a b' \"$x\"", "echo a\\ b ${x}"),
+            ("echo a{1..3}", "echo a1 a2 a3"),
+            (
+                "echo \\x41\\u{e9} '' $((1+2*(3-4)))",
+                "echo \\x41é \"\" $((1 + 2 * (3 - 4)))",
+            ),
+            (
+                "echo \"it's\" $(a | b && c)",
+                "echo it\\u{27}s $(a | b && c)",
+            ),
+            (
+                "echo ${a:-x} ${a:-1} ${a:1:2} ${a//\\u{2f}/-}",
+                "echo ${a:-x} ${a:-1} ${a: 1:2} ${a//\\u{2f}/-}",
+            ),
+            ("> out echo hi", "echo hi > out"),
+            (
+                "if a; then b; elif c; then d; else e; fi",
+                "if a; then b; elif c; then d; else e; fi",
+            ),
+            (
+                "for x in 1 2; do echo $x; done",
+                "for x in 1 2; do echo ${x}; done",
+            ),
+            (
+                "case $a in x|y) b;; *) ;; esac",
+                "case ${a} in x | y) b;; *) ;; esac",
+            ),
+            ("function f { echo $1; }", "function f { echo ${1}; }"),
+            (
+                "echo \"$@\" ${a[@]} ${a[-1]} ${m[$k]} ${#a[@]}",
+                "echo ${@} ${a[@]} ${a[-1]} ${m[${k}]} ${#a}",
+            ),
+        ];
+
+        for (source, printed) in cases {
+            let list = ParseCtx::from_chars(&mut source.chars()).unwrap();
+            assert_eq!(list.to_string(), printed);
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! Token buckets per user, channel and command, so a heavy command or a
+//! message with hundreds of lines can't be spammed
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serenity::model::id::{ChannelId, UserId};
+
+use crate::{
+    config::{RateLimit, RateLimits},
+    util::humanize::units::durations,
+};
+
+/// Past this many buckets, the full ones get dropped
+const PRUNE_LEN: usize = 4096;
+/// Longest wait told, refills can be configured to take forever
+const MAX_WAIT: Duration = Duration::from_secs(365 * 86400);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    User(UserId),
+    Channel(ChannelId),
+    Command(UserId, ChannelId, String),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Whether someone was told it's empty since it was last taken from
+    notified: bool,
+}
+
+impl Bucket {
+    fn full(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.burst),
+            updated: now,
+            notified: false,
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let refilled = now.duration_since(self.updated).as_secs_f64() / limit.refill;
+        self.tokens = (self.tokens + refilled).min(f64::from(limit.burst));
+        self.updated = now;
+    }
+
+    /// How long until there are `cost` tokens
+    fn wait(&self, limit: RateLimit, cost: f64) -> Duration {
+        let secs = (cost - self.tokens).max(0.0) * limit.refill;
+        Duration::try_from_secs_f64(secs)
+            .unwrap_or(MAX_WAIT)
+            .min(MAX_WAIT)
+    }
+}
+
+#[derive(Debug)]
+pub enum Limited {
+    /// Too many commands from the user or in the channel
+    Message(Duration),
+    Command {
+        command: String,
+        wait: Duration,
+    },
+}
+
+impl Display for Limited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Message(wait) => {
+                write!(f, "slow down, try again in {}", durations::short(*wait))
+            }
+            Self::Command { command, wait } => write!(
+                f,
+                "`{command}` is rate limited, try again in {}",
+                durations::short(*wait)
+            ),
+        }
+    }
+}
+
+impl super::CommandError for Limited {
+    fn code(&self) -> &'static str {
+        "E010"
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<HashMap<Key, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Takes a token per command list from both the author's and the
+    /// channel's buckets. A message can't cost more than a full bucket.
+    /// `Err(None)` if someone was already told to wait for the same
+    /// buckets, so spamming doesn't make the bot spam back.
+    pub fn message(
+        &self,
+        user: UserId,
+        channel: ChannelId,
+        lists: usize,
+    ) -> Result<(), Option<Limited>> {
+        self.take(
+            &[
+                (Key::User(user), self.limits.user),
+                (Key::Channel(channel), self.limits.channel),
+            ],
+            lists as f64,
+            Instant::now(),
+        )
+        .map_err(|(wait, notify)| notify.then_some(Limited::Message(wait)))
+    }
+
+    /// Takes a token for `command` if it has its own limit
+    pub fn command(&self, user: UserId, channel: ChannelId, command: &str) -> Result<(), Limited> {
+        let Some(&limit) = self.limits.commands.get(command) else {
+            return Ok(());
+        };
+        self.take(
+            &[(Key::Command(user, channel, command.to_owned()), limit)],
+            1.0,
+            Instant::now(),
+        )
+        .map_err(|(wait, _)| Limited::Command {
+            command: command.to_owned(),
+            wait,
+        })
+    }
+
+    /// Takes from every bucket or from none of them. Otherwise returns the
+    /// longest wait, and whether one of the empty buckets wasn't notified
+    /// yet, they all are after this.
+    fn take(
+        &self,
+        keys: &[(Key, RateLimit)],
+        cost: f64,
+        now: Instant,
+    ) -> Result<(), (Duration, bool)> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > PRUNE_LEN {
+            self.prune(&mut buckets, now);
+        }
+
+        let mut wait = Duration::ZERO;
+        let mut notify = false;
+        for (key, limit) in keys {
+            let bucket = buckets
+                .entry(key.clone())
+                .or_insert_with(|| Bucket::full(*limit, now));
+            bucket.refill(*limit, now);
+
+            let bucket_wait = bucket.wait(*limit, cost.min(f64::from(limit.burst)));
+            if !bucket_wait.is_zero() {
+                notify |= !bucket.notified;
+                bucket.notified = true;
+            }
+            wait = wait.max(bucket_wait);
+        }
+        if !wait.is_zero() {
+            return Err((wait, notify));
+        }
+
+        for (key, limit) in keys {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= cost.min(f64::from(limit.burst));
+                bucket.notified = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Full buckets are the same as no bucket
+    fn prune(&self, buckets: &mut HashMap<Key, Bucket>, now: Instant) {
+        buckets.retain(|key, bucket| {
+            let limit = match key {
+                Key::User(_) => Some(self.limits.user),
+                Key::Channel(_) => Some(self.limits.channel),
+                Key::Command(_, _, command) => self.limits.commands.get(command).copied(),
+            };
+            let Some(limit) = limit else {
+                return false;
+            };
+            bucket.refill(limit, now);
+            bucket.tokens < f64::from(limit.burst)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit {
+        burst: 3,
+        refill: 10.0,
+    };
+    const USER: UserId = UserId::new(1);
+    const OTHER_USER: UserId = UserId::new(2);
+    const CHANNEL: ChannelId = ChannelId::new(3);
+    const OTHER_CHANNEL: ChannelId = ChannelId::new(4);
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            user: LIMIT,
+            channel: RateLimit {
+                burst: 5,
+                refill: 10.0,
+            },
+            commands: [(String::from("heavy"), LIMIT)].into(),
+        })
+    }
+
+    #[test]
+    fn bucket_refills() {
+        let start = Instant::now();
+        let mut bucket = Bucket::full(LIMIT, start);
+        assert!(bucket.wait(LIMIT, 3.0).is_zero());
+        bucket.tokens -= 3.0;
+        assert_eq!(bucket.wait(LIMIT, 1.0), Duration::from_secs(10));
+
+        bucket.refill(LIMIT, start + Duration::from_secs(15));
+        assert_eq!(bucket.tokens, 1.5);
+        assert_eq!(bucket.wait(LIMIT, 2.0), Duration::from_secs(5));
+
+        // never past the burst
+        bucket.refill(LIMIT, start + Duration::from_secs(1000));
+        assert_eq!(bucket.tokens, 3.0);
+    }
+
+    #[test]
+    fn takes_up_to_burst_then_refills() {
+        let limiter = limiter();
+        let keys = [(Key::User(USER), LIMIT)];
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.take(&keys, 1.0, start), Ok(()));
+        }
+        assert_eq!(
+            limiter.take(&keys, 1.0, start),
+            Err((Duration::from_secs(10), true))
+        );
+        // told once per cooldown
+        assert_eq!(
+            limiter.take(&keys, 1.0, start + Duration::from_secs(5)),
+            Err((Duration::from_secs(5), false))
+        );
+
+        assert_eq!(
+            limiter.take(&keys, 1.0, start + Duration::from_secs(10)),
+            Ok(())
+        );
+        assert_eq!(
+            limiter.take(&keys, 1.0, start + Duration::from_secs(10)),
+            Err((Duration::from_secs(10), true))
+        );
+    }
+
+    #[test]
+    fn takes_all_or_nothing() {
+        let limiter = limiter();
+        let start = Instant::now();
+        let user = (Key::User(USER), LIMIT);
+        let channel = (Key::Channel(CHANNEL), LIMIT);
+
+        assert_eq!(
+            limiter.take(std::slice::from_ref(&user), 3.0, start),
+            Ok(())
+        );
+        assert!(limiter.take(&[user, channel.clone()], 1.0, start).is_err());
+        // the channel's bucket wasn't taken from
+        assert_eq!(limiter.take(&[channel], 3.0, start), Ok(()));
+    }
+
+    #[test]
+    fn warns_once() {
+        let limiter = limiter();
+        assert!(limiter.message(USER, CHANNEL, 3).is_ok());
+        assert!(matches!(
+            limiter.message(USER, CHANNEL, 1),
+            Err(Some(Limited::Message(_)))
+        ));
+        assert!(matches!(limiter.message(USER, CHANNEL, 1), Err(None)));
+        assert!(matches!(limiter.message(USER, OTHER_CHANNEL, 1), Err(None)));
+
+        // a message can't cost more than a full bucket
+        assert!(limiter.message(OTHER_USER, OTHER_CHANNEL, 100).is_ok());
+    }
+
+    #[test]
+    fn keys() {
+        let limiter = limiter();
+        // users share their channel's bucket
+        assert!(limiter.message(USER, CHANNEL, 3).is_ok());
+        assert!(limiter.message(OTHER_USER, CHANNEL, 2).is_ok());
+        assert!(matches!(
+            limiter.message(OTHER_USER, CHANNEL, 1),
+            Err(Some(_))
+        ));
+        assert!(limiter.message(OTHER_USER, OTHER_CHANNEL, 1).is_ok());
+
+        // commands per user and channel, unlimited ones aren't tracked
+        for _ in 0..3 {
+            assert!(limiter.command(USER, CHANNEL, "heavy").is_ok());
+        }
+        assert!(matches!(
+            limiter.command(USER, CHANNEL, "heavy"),
+            Err(Limited::Command { .. })
+        ));
+        assert!(limiter.command(USER, OTHER_CHANNEL, "heavy").is_ok());
+        assert!(limiter.command(OTHER_USER, CHANNEL, "heavy").is_ok());
+        for _ in 0..10 {
+            assert!(limiter.command(USER, CHANNEL, "light").is_ok());
+        }
+    }
+
+    #[test]
+    fn waits_are_capped() {
+        let limit = RateLimit {
+            burst: 1,
+            refill: f64::MAX,
+        };
+        let mut bucket = Bucket::full(limit, Instant::now());
+        bucket.tokens = 0.0;
+        assert_eq!(bucket.wait(limit, 1.0), MAX_WAIT);
+    }
+}
@@ -1,80 +1,307 @@
 pub mod commands;
+pub mod replies;
+pub mod reply;
 
-use std::ffi::OsString;
+use std::{sync::Mutex, time::Duration};
 
-use serenity::{async_trait, model::channel::Message, prelude::*};
+use serenity::{
+    async_trait,
+    builder::{CreateAllowedMentions, CreateMessage, EditMessage},
+    model::{
+        channel::Message,
+        event::MessageUpdateEvent,
+        guild::Guild,
+        id::{ChannelId, GuildId, MessageId},
+    },
+    prelude::*,
+};
 
-struct Handler<T: surrealdb::Connection> {
+/// What [`Handler::run`] made of a message
+enum Outcome {
+    /// No commands, or they can't run where it was sent
+    NoCommands,
+    /// Rate limited, and the author was already told to slow down
+    Skip,
+    Reply(reply::Reply),
+}
+
+struct Handler {
     config: crate::config::Schema,
-    #[allow(dead_code)]
-    db: surrealdb::Surreal<T>,
+    db: commands::Database,
+    /// Replies to recent messages, edited along with them
+    replies: Mutex<replies::Replies>,
+    rate_limiter: commands::ratelimit::RateLimiter,
+}
+
+impl Handler {
+    /// In `servers`, or in DMs if they're allowed
+    fn allowed(&self, guild_id: Option<GuildId>) -> bool {
+        match guild_id {
+            Some(guild_id) => {
+                self.config.servers.is_empty() || self.config.servers.contains(&guild_id)
+            }
+            None => self.config.allow_dms,
+        }
+    }
+
+    /// Runs the commands in `msg`
+    async fn run(&self, ctx: &Context, msg: &Message) -> Outcome {
+        if !self.allowed(msg.guild_id) {
+            return Outcome::NoCommands;
+        }
+
+        let prefix = self.config.prefix.to_string();
+        let limits = self.config.limits;
+        let maybe_cmds = commands::parser::MsgParser::new(&prefix, &msg.content)
+            .max_depth(limits.nesting_depth)
+            .parse();
+
+        let cmds = match maybe_cmds {
+            Ok(cmds) => cmds,
+            Err(err) => {
+                return Outcome::Reply(reply::Reply::error(
+                    err.render(&msg.content),
+                    &self.config.output,
+                ));
+            }
+        };
+        if cmds.is_empty() {
+            return Outcome::NoCommands;
+        }
+
+        let owner = self.config.owners.contains(&msg.author.id);
+        let rate_limiter = (!owner).then_some(&self.rate_limiter);
+        if let Some(rate_limiter) = rate_limiter {
+            match rate_limiter.message(msg.author.id, msg.channel_id, cmds.len()) {
+                Ok(()) => {}
+                Err(Some(err)) => {
+                    return Outcome::Reply(reply::Reply::error(
+                        err.to_string(),
+                        &self.config.output,
+                    ));
+                }
+                Err(None) => return Outcome::Skip,
+            }
+        }
+
+        // TODO: make debug/trace log macro
+        //let _ = msg.reply(&ctx.http, format!("`{:?}`", a.clone())).await;
+
+        let mut environ = commands::context::environ(ctx, msg, self.config.prefix);
+
+        let definitions =
+            commands::definitions::Definitions::load(&self.db, msg.author.id, msg.guild_id)
+                .await
+                .unwrap_or_else(|err| {
+                    // still runs, but nothing gets stored back
+                    println!("Error loading definitions {err:?}");
+                    Default::default()
+                });
+
+        let access =
+            commands::permissions::Access::load(ctx, msg, &self.db, &self.config.owners).await;
+        let mut executer = commands::RegistryExecuter::new(
+            commands::CommandContext {
+                ctx,
+                msg,
+                db: &self.db,
+            },
+            access,
+            rate_limiter,
+        );
+        let mut interpreter =
+            commands::interpreter::Interpreter::new(definitions, msg.content.clone(), limits);
+
+        let mut cmd_outputs = vec![];
+        let timeout = Duration::from_secs(limits.timeout);
+        let finished = tokio::time::timeout(timeout, async {
+            for cmd in cmds {
+                cmd_outputs.push(interpreter.run(cmd, &mut environ, &mut executer).await);
+                if interpreter.limit_hit().is_some() {
+                    break;
+                }
+            }
+        })
+        .await;
+
+        // what got defined before the timeout is kept
+        if let Err(err) = interpreter.definitions.store(&self.db).await {
+            println!("Error storing definitions {err:?}");
+        }
+
+        Outcome::Reply(match finished {
+            Ok(()) => reply::Reply::from_outputs(cmd_outputs, &self.config.output),
+            Err(_) => reply::Reply::error(
+                format!("message took longer than {}s", limits.timeout),
+                &self.config.output,
+            ),
+        })
+    }
+
+    /// Nothing in it pings, outputs can have any mention in them
+    async fn send_reply(&self, ctx: &Context, msg: &Message, reply: reply::Reply) {
+        let mut messages = reply.messages.into_iter();
+        let first = CreateMessage::new()
+            .content(messages.next().unwrap_or_default())
+            .allowed_mentions(CreateAllowedMentions::new())
+            .add_files(reply.files)
+            .reference_message(msg);
+
+        let reply_id = match msg.channel_id.send_message(&ctx.http, first).await {
+            Ok(reply) => reply.id,
+            Err(err) => {
+                println!("Error sending reply {err:?}");
+                return;
+            }
+        };
+        let mut ids = vec![reply_id];
+        ids.extend(Self::send_rest(ctx, msg.channel_id, messages).await);
+        self.replies.lock().unwrap().insert(msg.id, ids);
+    }
+
+    /// The reply gets edited in place, the messages it was split into are
+    /// sent again
+    async fn edit_reply(
+        &self,
+        ctx: &Context,
+        msg: &Message,
+        reply_ids: Vec<MessageId>,
+        reply: reply::Reply,
+    ) {
+        let mut messages = reply.messages.into_iter();
+        let edit = reply.files.into_iter().fold(
+            EditMessage::new()
+                .content(messages.next().unwrap_or_default())
+                .allowed_mentions(CreateAllowedMentions::new())
+                .remove_all_attachments(),
+            EditMessage::new_attachment,
+        );
+        if let Err(err) = msg.channel_id.edit_message(ctx, reply_ids[0], edit).await {
+            println!("Error editing reply {err:?}");
+        }
+        Self::delete(ctx, msg.channel_id, &reply_ids[1..]).await;
+
+        let mut ids = vec![reply_ids[0]];
+        ids.extend(Self::send_rest(ctx, msg.channel_id, messages).await);
+        self.replies.lock().unwrap().insert(msg.id, ids);
+    }
+
+    /// The messages after the reply, if it was split
+    async fn send_rest(
+        ctx: &Context,
+        channel_id: ChannelId,
+        messages: impl Iterator<Item = String>,
+    ) -> Vec<MessageId> {
+        let mut ids = vec![];
+        for content in messages {
+            let message = CreateMessage::new()
+                .content(content)
+                .allowed_mentions(CreateAllowedMentions::new());
+            match channel_id.send_message(&ctx.http, message).await {
+                Ok(message) => ids.push(message.id),
+                Err(err) => println!("Error sending reply {err:?}"),
+            }
+        }
+        ids
+    }
+
+    async fn delete(ctx: &Context, channel_id: ChannelId, reply_ids: &[MessageId]) {
+        for reply_id in reply_ids {
+            if let Err(err) = channel_id.delete_message(&ctx.http, reply_id).await {
+                println!("Error deleting reply {err:?}");
+            }
+        }
+    }
 }
 
 #[async_trait]
-impl<T: surrealdb::Connection> EventHandler for Handler<T> {
+impl EventHandler for Handler {
+    /// Leaves the guilds not in `servers`, the ones it was in before too
+    async fn guild_create(&self, ctx: Context, guild: Guild, _is_new: Option<bool>) {
+        if self.allowed(Some(guild.id)) {
+            return;
+        }
+
+        println!("Leaving unauthorized guild {} ({})", guild.name, guild.id);
+        if let Err(err) = guild.id.leave(&ctx.http).await {
+            println!("Error leaving guild {err:?}");
+        }
+    }
+
     async fn message(&self, ctx: Context, msg: Message) {
-        let maybe_cmds =
-            commands::parser::MsgParser::new(&self.config.prefix.to_string(), &msg.content).parse();
-
-        match maybe_cmds {
-            Ok(cmds) => {
-                if !cmds.is_empty() {
-                    // TODO: make debug/trace log macro
-                    //let _ = msg.reply(&ctx.http, format!("`{:?}`", a.clone())).await;
-
-                    let mut environ = commands::DefaultEnviron::default();
-                    environ.insert(
-                        String::from("USER"),
-                        commands::parser::EnvironValue::String(OsString::from(&msg.author.name)),
-                    );
-                    environ.insert(
-                        String::from("USERID"),
-                        commands::parser::EnvironValue::UNumber(msg.author.id.get() as u128)
-                    );
-
-                    let mut executer = commands::HardcodedExecuter;
-
-                    let mut output = String::new();
-                    for cmd in cmds {
-                        match cmd.resolve(&mut environ, &mut executer) {
-                            Ok(cmd_output) => {
-                                let Some(cmd_output) = cmd_output.as_string() else {
-                                    let _ = msg.reply(&ctx.http, "**err**: Unserializable Output");
-                                    return;
-                                };
-                                if !cmd_output.is_empty() {
-                                    output += cmd_output.to_string_lossy().as_ref();
-                                    output += "\n";
-                                }
-                            }
-                            Err(err) => {
-                                let _ = msg
-                                    .reply(&ctx.http, format!("**execution error**: `{err:?}`"))
-                                    .await;
-                                return;
-                            }
-                        };
-                    }
-                    let _ = msg.reply(&ctx.http, format!("```\n{output}\n```")).await;
+        if let Outcome::Reply(reply) = self.run(&ctx, &msg).await {
+            self.send_reply(&ctx, &msg, reply).await;
+        }
+    }
+
+    /// Runs the edited message again, its reply gets edited, or deleted if
+    /// there's no command left. It stays as it is if the edit is rate limited.
+    async fn message_update(
+        &self,
+        ctx: Context,
+        _old_if_available: Option<Message>,
+        new: Option<Message>,
+        event: MessageUpdateEvent,
+    ) {
+        // embeds getting resolved are updates too
+        if event.content.is_none() {
+            return;
+        }
+
+        let msg = match new {
+            Some(msg) => msg,
+            None => match event.channel_id.message(&ctx, event.id).await {
+                Ok(msg) => msg,
+                Err(err) => {
+                    println!("Error fetching edited message {err:?}");
+                    return;
                 }
+            },
+        };
+
+        let reply_ids = self.replies.lock().unwrap().get(msg.id);
+        match (self.run(&ctx, &msg).await, reply_ids) {
+            (Outcome::Reply(reply), Some(reply_ids)) => {
+                self.edit_reply(&ctx, &msg, reply_ids, reply).await
             }
-            Err(err) => {
-                let _ = msg.reply(&ctx.http, format!("**err**: `{err:?}`")).await;
+            (Outcome::Reply(reply), None) => self.send_reply(&ctx, &msg, reply).await,
+            (Outcome::NoCommands, Some(reply_ids)) => {
+                self.replies.lock().unwrap().remove(msg.id);
+                Self::delete(&ctx, msg.channel_id, &reply_ids).await;
             }
+            (Outcome::NoCommands, None) | (Outcome::Skip, _) => {}
+        }
+    }
+
+    async fn message_delete(
+        &self,
+        ctx: Context,
+        channel_id: ChannelId,
+        deleted_message_id: MessageId,
+        _guild_id: Option<GuildId>,
+    ) {
+        let Some(reply_ids) = self.replies.lock().unwrap().remove(deleted_message_id) else {
+            return;
         };
+        Self::delete(&ctx, channel_id, &reply_ids).await;
     }
 }
 
-pub async fn load(
-    config: crate::config::Schema,
-    db: surrealdb::Surreal<impl surrealdb::Connection>,
-) {
-    let intents = GatewayIntents::GUILD_MESSAGES
+pub async fn load(config: crate::config::Schema, db: commands::Database) {
+    // GUILDS fills the cache `$CHANNEL`, `$GUILD`, `$MEMBERS` and the
+    // author's permissions are read from
+    let intents = GatewayIntents::GUILDS
+        | GatewayIntents::GUILD_MESSAGES
         | GatewayIntents::DIRECT_MESSAGES
         | GatewayIntents::MESSAGE_CONTENT;
 
     let mut client = Client::builder(&config.token, intents)
-        .event_handler(Handler { config, db })
+        .event_handler(Handler {
+            rate_limiter: commands::ratelimit::RateLimiter::new(config.rate_limits.clone()),
+            config,
+            db,
+            replies: Default::default(),
+        })
         .await
         .expect("Error creating client");
 
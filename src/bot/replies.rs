@@ -0,0 +1,42 @@
+//! Which replies the bot sent to which message, so edits and deletions of
+//! the message can follow up on them
+
+use std::collections::{HashMap, VecDeque};
+
+use serenity::model::id::MessageId;
+
+/// Only the most recent messages are kept, older ones are rarely edited
+pub const MAX_TRACKED: usize = 1024;
+
+#[derive(Debug, Default)]
+pub struct Replies {
+    /// The reply first, then the messages it was split into
+    replies: HashMap<MessageId, Vec<MessageId>>,
+    /// Oldest first
+    order: VecDeque<MessageId>,
+}
+
+impl Replies {
+    pub fn insert(&mut self, msg: MessageId, replies: Vec<MessageId>) {
+        if self.replies.insert(msg, replies).is_some() {
+            return;
+        }
+
+        self.order.push_back(msg);
+        if self.order.len() > MAX_TRACKED {
+            if let Some(oldest) = self.order.pop_front() {
+                self.replies.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn get(&self, msg: MessageId) -> Option<Vec<MessageId>> {
+        self.replies.get(&msg).cloned()
+    }
+
+    pub fn remove(&mut self, msg: MessageId) -> Option<Vec<MessageId>> {
+        let reply = self.replies.remove(&msg)?;
+        self.order.retain(|id| *id != msg);
+        Some(reply)
+    }
+}
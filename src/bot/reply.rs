@@ -0,0 +1,347 @@
+//! Turns command outputs into Discord messages, text goes in code blocks and
+//! blobs as attachments
+
+use crate::util::humanize::units::sizes;
+
+use super::commands::parser::{EnvironValue, Output};
+
+use serenity::builder::CreateAttachment;
+
+/// `extension`, `mime type` and the bytes a file starts with
+const FILE_TYPES: &[(&str, &str, &[u8])] = &[
+    ("png", "image/png", b"\x89PNG\r\n\x1a\n"),
+    ("jpg", "image/jpeg", b"\xff\xd8\xff"),
+    ("gif", "image/gif", b"GIF87a"),
+    ("gif", "image/gif", b"GIF89a"),
+    ("pdf", "application/pdf", b"%PDF-"),
+    ("zip", "application/zip", b"PK\x03\x04"),
+    ("gz", "application/gzip", b"\x1f\x8b"),
+    ("ogg", "audio/ogg", b"OggS"),
+    ("flac", "audio/flac", b"fLaC"),
+    ("mp3", "audio/mpeg", b"ID3"),
+    ("webm", "video/webm", b"\x1a\x45\xdf\xa3"),
+];
+
+/// Closes every block
+const CLOSING_FENCE: &str = "```";
+
+/// Discord doesn't take more files in a message
+const MAX_FILES: usize = 10;
+/// Room is left for `output.txt` and `stderr.txt`
+const MAX_BLOBS: usize = MAX_FILES - 2;
+
+/// Guesses the extension and mime type of `data` from its first bytes
+pub fn file_type(data: &[u8]) -> (&'static str, &'static str) {
+    if let Some((extension, mime, _)) = FILE_TYPES
+        .iter()
+        .find(|(_, _, magic)| data.starts_with(magic))
+    {
+        return (extension, mime);
+    }
+
+    // these have the type after a size
+    match (data.get(..4), data.get(4..8), data.get(8..12)) {
+        (Some(b"RIFF"), _, Some(b"WEBP")) => return ("webp", "image/webp"),
+        (Some(b"RIFF"), _, Some(b"WAVE")) => return ("wav", "audio/wav"),
+        (_, Some(b"ftyp"), _) => return ("mp4", "video/mp4"),
+        _ => {}
+    }
+
+    if std::str::from_utf8(data).is_ok() {
+        ("txt", "text/plain")
+    } else {
+        ("bin", "application/octet-stream")
+    }
+}
+
+/// Opens a block, Discord colors text with escape codes in ```ansi ones
+fn opening_fence(body: &str) -> &'static str {
+    match body.contains('\x1b') {
+        true => "```ansi\n",
+        false => "```\n",
+    }
+}
+
+/// Backticks in a row get a zero width space between them, so the body
+/// can't close its block
+fn escape_fences(body: &str) -> String {
+    let mut escaped = String::with_capacity(body.len());
+    for ch in body.chars() {
+        if ch == '`' && escaped.ends_with('`') {
+            escaped.push('\u{200b}');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+/// A part of the reply, in a code block under `header`, or attached as
+/// `filename` if it doesn't fit
+struct Section {
+    header: String,
+    body: String,
+    filename: &'static str,
+}
+
+/// What gets sent for a message, the first message is the reply and
+/// carries the files, the others follow it
+#[derive(Debug, Default)]
+pub struct Reply {
+    pub messages: Vec<String>,
+    pub files: Vec<CreateAttachment>,
+}
+
+impl Reply {
+    /// `err` in a code block, as for parse errors
+    pub fn error(err: String, config: &crate::config::Output) -> Self {
+        let section = Section {
+            header: String::from("**err**:\n"),
+            body: err,
+            filename: "error.txt",
+        };
+        Self::build(vec![section], vec![], config)
+    }
+
+    /// Stdouts go together in a code block, or as attachments if they're
+    /// blobs, stderrs go below with the last status. Blobs past
+    /// [`MAX_BLOBS`] are left out.
+    pub fn from_outputs(outputs: Vec<Output>, config: &crate::config::Output) -> Self {
+        let mut output = String::new();
+        let mut errors = String::new();
+        let mut status = 0;
+        let mut files = vec![];
+        let mut dropped = 0;
+        for cmd_output in outputs {
+            status = cmd_output.status;
+
+            let cmd_errors = cmd_output.stderr.to_string_lossy();
+            if !cmd_errors.is_empty() {
+                errors += cmd_errors.as_ref();
+                if !errors.ends_with('\n') {
+                    errors += "\n";
+                }
+            }
+
+            match cmd_output.stdout {
+                EnvironValue::Blob(_) if files.len() == MAX_BLOBS => dropped += 1,
+                EnvironValue::Blob(blob) => files.push(Self::attachment(blob, files.len())),
+                stdout => {
+                    let stdout = stdout.as_string().unwrap_or_default();
+                    if !stdout.is_empty() {
+                        output += stdout.to_string_lossy().as_ref();
+                        output += "\n";
+                    }
+                }
+            }
+        }
+
+        if dropped > 0 {
+            errors += &format!(
+                "{dropped} more blob{} not attached, a message can only have {MAX_FILES} files\n",
+                if dropped == 1 { " was" } else { "s were" }
+            );
+        }
+
+        let mut sections = vec![];
+        if !output.is_empty() || (errors.is_empty() && files.is_empty()) {
+            sections.push(Section {
+                header: String::new(),
+                body: output,
+                filename: "output.txt",
+            });
+        }
+        if !errors.is_empty() {
+            sections.push(Section {
+                header: format!("**stderr** (status {status}):\n"),
+                body: errors,
+                filename: "stderr.txt",
+            });
+        }
+        Self::build(sections, files, config)
+    }
+
+    /// Named `blob.png`, `blob-2.png`... and described by its type and size
+    fn attachment(blob: Vec<u8>, index: usize) -> CreateAttachment {
+        let (extension, mime) = file_type(&blob);
+        let filename = match index {
+            0 => format!("blob.{extension}"),
+            index => format!("blob-{}.{extension}", index + 1),
+        };
+        let size = sizes::bytes_to_binary(blob.len() as f64, 2);
+
+        let mut attachment = CreateAttachment::bytes(blob, filename);
+        attachment.description = Some(format!("{mime}, {size}"));
+        attachment
+    }
+
+    /// The longest sections get attached until the rest fits in
+    /// `max_messages`
+    fn build(
+        sections: Vec<Section>,
+        mut files: Vec<CreateAttachment>,
+        config: &crate::config::Output,
+    ) -> Self {
+        let mut attached = vec![false; sections.len()];
+        let messages = loop {
+            let messages = Self::pack(&sections, &attached, config.message_len);
+            let longest = sections
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !attached[*i])
+                .max_by_key(|(_, section)| section.body.len());
+
+            match longest {
+                Some((i, _)) if messages.len() > config.max_messages.max(1) => attached[i] = true,
+                _ => break messages,
+            }
+        };
+
+        for (section, _) in sections
+            .into_iter()
+            .zip(attached)
+            .filter(|(_, attached)| *attached)
+        {
+            files.push(CreateAttachment::bytes(section.body, section.filename));
+        }
+        Self { messages, files }
+    }
+
+    /// Code blocks fill messages up to `message_len` chars, attached sections
+    /// only leave their header
+    fn pack(sections: &[Section], attached: &[bool], message_len: usize) -> Vec<String> {
+        let mut messages = vec![String::new()];
+        let mut len = 0;
+        for (section, attached) in sections.iter().zip(attached) {
+            let header_len = section.header.chars().count();
+            let blocks: Vec<_> = if *attached {
+                vec![section.header.clone()]
+            } else {
+                let fence = opening_fence(&section.body);
+                let chunk_len =
+                    message_len.saturating_sub(header_len + fence.len() + CLOSING_FENCE.len());
+                chunks(&escape_fences(&section.body), chunk_len)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, chunk)| match i {
+                        0 => format!("{}{fence}{chunk}{CLOSING_FENCE}", section.header),
+                        _ => format!("{fence}{chunk}{CLOSING_FENCE}"),
+                    })
+                    .collect()
+            };
+
+            for block in blocks {
+                let block_len = block.chars().count();
+                if len > 0 && len + block_len > message_len {
+                    messages.push(String::new());
+                    len = 0;
+                }
+                messages.last_mut().unwrap().push_str(&block);
+                len += block_len;
+            }
+        }
+        messages
+    }
+}
+
+/// Splits `text` in chunks of at most `max` chars, each ending in a newline.
+/// Lines only get split when they don't fit in a chunk of their own,
+/// trailing empty lines are dropped.
+fn chunks(text: &str, max: usize) -> Vec<String> {
+    let text = text.trim_end_matches('\n');
+    // room for the added newline
+    let max = max.saturating_sub(1).max(1);
+
+    let mut chunks = vec![String::new()];
+    let mut len = 0;
+    for line in text.split_inclusive('\n') {
+        let line_len = line.chars().count();
+        for (i, ch) in line.chars().enumerate() {
+            if len == max || (i == 0 && len > 0 && len + line_len > max) {
+                chunks.push(String::new());
+                len = 0;
+            }
+            chunks.last_mut().unwrap().push(ch);
+            len += 1;
+        }
+    }
+
+    for chunk in &mut chunks {
+        if !chunk.ends_with('\n') {
+            chunk.push('\n');
+        }
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> Output {
+        EnvironValue::String(text.into()).into()
+    }
+
+    #[test]
+    fn chunks_fit() {
+        let line = "a".repeat(1999);
+        assert_eq!(chunks(&line, 2000), [format!("{line}\n")]);
+
+        let long = "b".repeat(4500);
+        let split = chunks(&long, 2000);
+        assert_eq!(split.len(), 3);
+        assert!(split.iter().all(|chunk| chunk.chars().count() <= 2000));
+        assert_eq!(split.concat().replace('\n', ""), long);
+
+        // lines that fit aren't split across chunks
+        let lines = format!("{}\n{}", "c".repeat(1500), "d".repeat(1000));
+        assert_eq!(
+            chunks(&lines, 2000),
+            [
+                format!("{}\n", "c".repeat(1500)),
+                format!("{}\n", "d".repeat(1000))
+            ]
+        );
+    }
+
+    #[test]
+    fn messages_fit() {
+        let config = crate::config::Output::default();
+        let reply = Reply::from_outputs(vec![text(&"e\n".repeat(2500))], &config);
+        assert_eq!(reply.messages.len(), config.max_messages);
+        assert!(reply
+            .messages
+            .iter()
+            .all(|message| message.chars().count() <= config.message_len));
+        assert!(reply.files.is_empty());
+    }
+
+    #[test]
+    fn fences_are_escaped() {
+        let config = crate::config::Output::default();
+        let reply = Reply::from_outputs(vec![text("```\n@everyone\n````")], &config);
+        assert_eq!(reply.messages[0].matches("``").count(), 2);
+    }
+
+    #[test]
+    fn attachments_are_capped() {
+        let config = crate::config::Output::default();
+        let blobs = (0..12)
+            .map(|i| EnvironValue::Blob(vec![i]).into())
+            .collect();
+        let reply = Reply::from_outputs(blobs, &config);
+        assert_eq!(reply.files.len(), MAX_BLOBS);
+        assert!(reply.messages[0].contains("4 more blobs were not attached"));
+
+        let long = "f\n".repeat(5000);
+        let mut outputs: Vec<Output> = (0..12)
+            .map(|i| EnvironValue::Blob(vec![i]).into())
+            .collect();
+        outputs.push(Output {
+            status: 1,
+            stdout: EnvironValue::String(long.clone().into()),
+            stderr: long.into(),
+        });
+        let reply = Reply::from_outputs(outputs, &config);
+        assert_eq!(reply.files.len(), MAX_FILES);
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use serde::Deserialize;
 use serenity::model::id::{GuildId, UserId};
 
@@ -8,6 +10,123 @@ pub struct SurrealDB {
     pub password: String,
 }
 
+/// How command outputs are sent back
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Output {
+    /// Chars per message, Discord doesn't take more than 2000
+    pub message_len: usize,
+    /// Long text is split across up to this many messages, past that it's
+    /// attached as a file instead
+    pub max_messages: usize,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self {
+            message_len: 2000,
+            max_messages: 3,
+        }
+    }
+}
+
+/// How much a single message can do, hitting a limit fails the command
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct Limits {
+    /// Subshells, quotes and blocks nested in each other
+    pub nesting_depth: usize,
+    /// Args a command can have once expanded
+    pub expanded_args: usize,
+    /// Bytes in any single value, outputs and variables included
+    pub output_bytes: usize,
+    /// Commands ran by a message, loop iterations and function calls
+    /// included
+    pub commands: usize,
+    /// Function calls, subshells and blocks running inside each other,
+    /// each level takes stack space
+    pub run_depth: usize,
+    /// Seconds a message can run for
+    pub timeout: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            nesting_depth: 32,
+            expanded_args: 1024,
+            output_bytes: 1 << 20,
+            commands: 10_000,
+            run_depth: 32,
+            timeout: 10,
+        }
+    }
+}
+
+/// A token bucket, `burst` in a row then one more every `refill` seconds
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(try_from = "RateLimitFields")]
+pub struct RateLimit {
+    pub burst: u32,
+    pub refill: f64,
+}
+
+#[derive(Deserialize)]
+struct RateLimitFields {
+    burst: u32,
+    refill: f64,
+}
+
+impl TryFrom<RateLimitFields> for RateLimit {
+    type Error = String;
+
+    fn try_from(RateLimitFields { burst, refill }: RateLimitFields) -> Result<Self, String> {
+        if burst == 0 {
+            do yeet String::from("rate limit `burst` has to be at least 1");
+        }
+        if !refill.is_finite() || refill <= 0.0 {
+            do yeet format!(
+                "rate limit `refill` has to be a positive number of seconds, not {refill}"
+            );
+        }
+        Ok(Self { burst, refill })
+    }
+}
+
+/// How often commands can run, the bot's owners aren't limited
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimits {
+    /// Commands a user sends, each line of a message counts
+    pub user: RateLimit,
+    /// Commands sent in a channel, by anyone
+    pub channel: RateLimit,
+    /// Runs of these commands by a user in a channel, loops included
+    pub commands: HashMap<String, RateLimit>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            user: RateLimit {
+                burst: 10,
+                refill: 2.0,
+            },
+            channel: RateLimit {
+                burst: 30,
+                refill: 1.0,
+            },
+            commands: HashMap::from([(
+                String::from("memusage"),
+                RateLimit {
+                    burst: 2,
+                    refill: 10.0,
+                },
+            )]),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Schema {
     pub token: String,
@@ -15,11 +134,25 @@ pub struct Schema {
     pub prefix: char,
     #[serde(default)]
     pub owners: Vec<UserId>,
+    /// Guilds the bot runs in and stays in, every one if empty
     #[serde(default)]
     pub servers: Vec<GuildId>,
+    /// Whether commands run in DMs too
+    #[serde(default = "__default_allow_dms")]
+    pub allow_dms: bool,
+    #[serde(default)]
+    pub output: Output,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub rate_limits: RateLimits,
     pub surrealdb: SurrealDB,
 }
 
 fn __default_prefix() -> char {
     '!'
 }
+
+fn __default_allow_dms() -> bool {
+    true
+}
@@ -1,10 +1,4 @@
-#![feature(
-    generic_arg_infer,
-    iterator_try_collect,
-    iter_intersperse,
-    slice_split_once,
-    yeet_expr
-)]
+#![feature(iterator_try_collect, slice_split_once, yeet_expr)]
 
 pub mod args;
 pub mod bot;
@@ -30,6 +30,37 @@ pub mod units {
         }
     }
 
+    pub mod durations {
+        use std::time::Duration;
+
+        static UNITS: &[(&str, u64)] = &[("d", 86400), ("h", 3600), ("m", 60), ("s", 1)];
+
+        /// The two largest units, as in `1m 20s`. The last one is rounded up,
+        /// so it never reads shorter than it is.
+        pub fn short(duration: Duration) -> String {
+            let secs = duration
+                .as_secs()
+                .saturating_add(u64::from(duration.subsec_nanos() > 0));
+            let Some(largest) = UNITS.iter().position(|(_, unit_secs)| secs >= *unit_secs) else {
+                return String::from("0s");
+            };
+            let (_, last_secs) = UNITS[(largest + 1).min(UNITS.len() - 1)];
+            let mut secs = secs.div_ceil(last_secs).saturating_mul(last_secs);
+
+            let mut parts = vec![];
+            for (unit, unit_secs) in UNITS {
+                if secs >= *unit_secs && parts.len() < 2 {
+                    parts.push(format!("{}{unit}", secs / unit_secs));
+                    secs %= unit_secs;
+                } else if !parts.is_empty() {
+                    // only adjacent units, `1h 5s` reads as a typo
+                    break;
+                }
+            }
+            parts.join(" ")
+        }
+    }
+
     pub trait Normalizable: DivAssign + PartialOrd + Copy {}
     impl<T: DivAssign + PartialOrd + Copy> Normalizable for T {}
 
@@ -68,3 +99,21 @@ pub mod units {
         (amount, unit_idx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::units::durations::short;
+
+    use std::time::Duration;
+
+    #[test]
+    fn short_durations() {
+        assert_eq!(short(Duration::ZERO), "0s");
+        assert_eq!(short(Duration::from_millis(1)), "1s");
+        assert_eq!(short(Duration::from_secs(80)), "1m 20s");
+        assert_eq!(short(Duration::from_secs(3605)), "1h 1m");
+        assert_eq!(short(Duration::from_millis(3_599_500)), "1h");
+        assert_eq!(short(Duration::from_secs(86400 + 3600 - 1)), "1d 1h");
+        assert_eq!(short(Duration::MAX), "213503982334601d 7h");
+    }
+}